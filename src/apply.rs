@@ -0,0 +1,909 @@
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::io::prelude::*;
+use crate::common::*;
+use crate::error::{Result, VsError};
+use crate::filter::{Filter, Overlay, Region};
+use crate::reader::{DeltaReader, Op};
+use crate::sparse::{data_extents, data_extents_between};
+use crate::storage::{stream_len, Storage};
+
+/*
+ * Controls how a delta is applied.
+ */
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    // external mode: skip blocks of zeros by seeking forwards, creating a sparse file_b
+    pub sparse: bool,
+    // check that file_a matches OP_HASH_A before applying the delta, and that
+    // the result matches OP_HASH_B afterwards.  In-place, file_a is always
+    // checked, as applying the delta to the wrong file would ruin it.
+    pub verify: bool,
+    // hash the files on this many threads, which gives the same hashes
+    pub threads: usize,
+    // in-place: zero blocks by punching holes in file_a, rather than writing zeros
+    pub punch_holes: bool,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> ApplyOptions {
+        ApplyOptions { sparse: true, verify: true, threads: 1, punch_holes: true }
+    }
+}
+
+/* 
+ * Copies "num" bytes from src to dst.
+ */
+fn copy_data<W: Write, R: Read>(dst: &mut W, src: &mut R, num: u64) -> Result<()> {
+    const OP_SKIP_CHUNKSIZE: usize = 1024 * 1024;
+    const OP_SKIP_CHUNKLEN: u64 = OP_SKIP_CHUNKSIZE as u64;
+
+    let num_chunks = num / OP_SKIP_CHUNKLEN;
+    let remainder = num - num_chunks * OP_SKIP_CHUNKLEN;
+
+    let mut copybuf = vec![0xFFu8; OP_SKIP_CHUNKSIZE];
+    for _ in 0..num_chunks {
+        //let pos = src.seek(SeekFrom::Current(0)).unwrap();
+        //println!("pos: {:?}", pos);
+        src.read_exact(&mut copybuf)?;
+        //println!("copybuf {:X?}", copybuf);
+        dst.write_all(&copybuf)?;
+    }
+
+    let mut copybuf = vec![0u8; remainder as usize];
+    src.read_exact(&mut copybuf)?;
+    //println!("copybuf {:X?}", copybuf);
+    dst.write_all(&copybuf)?;
+
+    Ok(())
+}
+
+/* 
+ * Copies "num" bytes from src to dst.
+ * 
+ * Skips blocks of zeros by seeking forwards, creating a sparse file.
+ */
+fn sparse_copy_data<S: Storage, R: Read>(dst: &mut S, src: &mut R, num: u64) -> Result<()> {
+    const OP_SKIP_CHUNKSIZE: usize = 4096;
+    const OP_SKIP_CHUNKLEN: u64 = OP_SKIP_CHUNKSIZE as u64;
+
+    let num_chunks = num / OP_SKIP_CHUNKLEN;
+    let remainder = num - num_chunks * OP_SKIP_CHUNKLEN;
+
+    let mut copybuf = vec![0xFFu8; OP_SKIP_CHUNKSIZE];
+    for _ in 0..num_chunks {
+        //let pos = src.seek(SeekFrom::Current(0)).unwrap();
+        src.read_exact(&mut copybuf)?;
+        if is_zero(&copybuf) {
+            //println!("pos: {:?} (zeros)", pos);
+            dst.seek(SeekFrom::Current(OP_SKIP_CHUNKLEN as i64))?;
+        } else {
+            //println!("pos: {:?}", pos);
+            //println!("copybuf {:X?}", copybuf);
+            dst.write_all(&copybuf)?;
+        }
+    }
+
+    let mut copybuf = vec![0u8; remainder as usize];
+    src.read_exact(&mut copybuf)?;
+    if is_zero(&copybuf) {
+        dst.seek(SeekFrom::Current(remainder as i64))?;
+    } else {
+        //println!("copybuf {:X?}", copybuf);
+        dst.write_all(&copybuf)?;
+    }
+
+    // TODO: these three lines only need to be executed if the last thing to happen was a dst.seek() after an is_zero.
+    let dst_pos = dst.stream_position()?;
+    //println!("dst_pos: {:?} (zeros)", dst_pos);
+    dst.set_len(dst_pos)?;
+
+    Ok(())
+}
+
+/*
+ * Copies "num" bytes from src to dst, at their positions, with
+ * Storage::copy_range(), so that the kernel copies them, or dst shares src's
+ * blocks.  Whatever it doesn't copy is read and written.
+ *
+ * If "sparse" is set, holes in src are left as holes in dst.
+ */
+fn clone_data<S: Storage>(dst: &mut S, mut src: &File, num: u64, sparse: bool) -> Result<()> {
+    let pos = src.stream_position()?;
+    let end = pos + num;
+    let extents = if sparse { data_extents_between(src, pos, end) } else { None };
+    let mut done = pos;
+    for extent in extents.unwrap_or_else(|| vec![Range { start: pos, end }]) {
+        if extent.start > done {
+            seek_data(dst, extent.start - done)?;
+        }
+        let len = extent.end - extent.start;
+        let copied = dst.copy_range(src, extent.start, len)?;
+        if copied < len {
+            src.seek(SeekFrom::Start(extent.start + copied))?;
+            if sparse {
+                sparse_copy_data(dst, &mut src, len - copied)?;
+            } else {
+                copy_data(dst, &mut src, len - copied)?;
+            }
+        }
+        done = extent.end;
+    }
+    if end > done {
+        seek_data(dst, end - done)?;
+    }
+    src.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/*
+ * Moves dst forwards by "num" bytes, extending it if necessary.
+ *
+ * If nothing has been written there, those bytes read as zeros.
+ */
+fn seek_data<S: Storage>(dst: &mut S, num: u64) -> Result<()> {
+    let pos = dst.seek(SeekFrom::Current(num as i64))?;
+    if stream_len(dst)? < pos {
+        dst.set_len(pos)?;
+    }
+    Ok(())
+}
+
+/* 
+ * Zeros "num" bytes of dst.
+ * 
+ * Only blocks which contain non-zero bytes are written.
+ */
+fn zero_data<S: Storage>(dst: &mut S, num: u64) -> Result<()> {
+    const OP_HOLE_CHUNKSIZE: usize = 4096;
+    const OP_HOLE_CHUNKLEN: u64 = OP_HOLE_CHUNKSIZE as u64;
+
+    let pos = dst.stream_position()?;
+    let existing = min(num, stream_len(dst)?.saturating_sub(pos));
+
+    let mut buf = vec![0u8; OP_HOLE_CHUNKSIZE];
+    let zeros = vec![0u8; OP_HOLE_CHUNKSIZE];
+    let mut remaining = existing;
+    while remaining > 0 {
+        let chunk = min(remaining, OP_HOLE_CHUNKLEN) as usize;
+        dst.read_exact(&mut buf[..chunk])?;
+        if !is_zero(&buf[..chunk]) {
+            dst.seek(SeekFrom::Current(-(chunk as i64)))?;
+            dst.write_all(&zeros[..chunk])?;
+        }
+        remaining -= chunk as u64;
+    }
+
+    // anything beyond the end of dst is zero once dst has been extended
+    seek_data(dst, num - existing)
+}
+
+/*
+ * Zeros "num" bytes of dst, like zero_data(), but by punching a hole, so that
+ * their blocks are deallocated, if dst supports that.
+ */
+fn punch_data<S: Storage>(dst: &mut S, num: u64) -> Result<()> {
+    let pos = dst.stream_position()?;
+    let existing = min(num, stream_len(dst)?.saturating_sub(pos));
+    if existing == 0 || !dst.punch_hole(pos, existing)? {
+        return zero_data(dst, num);
+    }
+    seek_data(dst, num)
+}
+
+/*
+ * Copies "num" bytes from src to dst.
+ *
+ * Blocks of zeros are zeroed with punch_data(), so that they become holes.
+ */
+fn punch_copy_data<S: Storage, R: Read>(dst: &mut S, src: &mut R, num: u64) -> Result<()> {
+    const OP_DIFF_CHUNKSIZE: usize = 4096;
+    const OP_DIFF_CHUNKLEN: u64 = OP_DIFF_CHUNKSIZE as u64;
+
+    let mut copybuf = vec![0u8; OP_DIFF_CHUNKSIZE];
+    let mut zeros = 0; // which have yet to be punched, so dst is still before them
+    let mut done = 0;
+    while done < num {
+        let chunk = min(num - done, OP_DIFF_CHUNKLEN) as usize;
+        src.read_exact(&mut copybuf[..chunk])?;
+        if is_zero(&copybuf[..chunk]) {
+            zeros += chunk as u64;
+        } else {
+            if zeros > 0 {
+                punch_data(dst, zeros)?;
+                zeros = 0;
+            }
+            dst.write_all(&copybuf[..chunk])?;
+        }
+        done += chunk as u64;
+    }
+    if zeros > 0 {
+        punch_data(dst, zeros)?;
+    }
+    Ok(())
+}
+
+/* 
+ * Copies "num" bytes at "from" in dst to "to" in dst, leaving dst positioned
+ * after them.
+ * 
+ * The regions may overlap.  If "from" is before "to", the copy repeats the
+ * "to - from" bytes before "to", like an LZ77 match.
+ */
+fn move_data<S: Storage>(dst: &mut S, from: u64, to: u64, num: u64) -> Result<()> {
+    const OP_COPY_CHUNKSIZE: usize = 1024 * 1024;
+    const OP_COPY_CHUNKLEN: u64 = OP_COPY_CHUNKSIZE as u64;
+
+    // never read anything which this copy has yet to write
+    let chunklen = if from < to {
+        min(to - from, OP_COPY_CHUNKLEN)
+    } else {
+        OP_COPY_CHUNKLEN
+    };
+
+    let mut copybuf = vec![0u8; chunklen as usize];
+    let mut done = 0;
+    while done < num {
+        let chunk = min(num - done, chunklen) as usize;
+        dst.seek(SeekFrom::Start(from + done))?;
+        dst.read_exact(&mut copybuf[..chunk])?;
+        dst.seek(SeekFrom::Start(to + done))?;
+        dst.write_all(&copybuf[..chunk])?;
+        done += chunk as u64;
+    }
+    dst.seek(SeekFrom::Start(to + num))?;
+    Ok(())
+}
+
+/*
+ * Combines "num" bytes of base with "num" bytes of data, byte by byte, with
+ * "combine", writing the result to dst.
+ */
+fn combine_data<W: Write, A: Read, R: Read>(dst: &mut W, base: &mut A, data: &mut R, num: u64, combine: fn(u8, u8) -> u8) -> Result<()> {
+    const OP_ADD_CHUNKSIZE: usize = 64 * 1024;
+    const OP_ADD_CHUNKLEN: u64 = OP_ADD_CHUNKSIZE as u64;
+
+    let mut basebuf = vec![0u8; OP_ADD_CHUNKSIZE];
+    let mut databuf = vec![0u8; OP_ADD_CHUNKSIZE];
+    let mut done = 0;
+    while done < num {
+        let chunk = min(num - done, OP_ADD_CHUNKLEN) as usize;
+        base.read_exact(&mut basebuf[..chunk])?;
+        data.read_exact(&mut databuf[..chunk])?;
+        for (byte, diff) in basebuf[..chunk].iter_mut().zip(databuf[..chunk].iter()) {
+            *byte = combine(*byte, *diff);
+        }
+        dst.write_all(&basebuf[..chunk])?;
+        done += chunk as u64;
+    }
+    Ok(())
+}
+
+/*
+ * Like combine_data(), but the base is "num" bytes at "from" in dst, and the
+ * result is written to "to", which must not be after "from".  dst is left
+ * positioned after the result.
+ */
+fn combine_in_place<S: Storage, R: Read>(dst: &mut S, from: u64, to: u64, data: &mut R, num: u64, combine: fn(u8, u8) -> u8) -> Result<()> {
+    const OP_ADD_CHUNKSIZE: usize = 64 * 1024;
+    const OP_ADD_CHUNKLEN: u64 = OP_ADD_CHUNKSIZE as u64;
+
+    let mut basebuf = vec![0u8; OP_ADD_CHUNKSIZE];
+    let mut databuf = vec![0u8; OP_ADD_CHUNKSIZE];
+    let mut done = 0;
+    while done < num {
+        let chunk = min(num - done, OP_ADD_CHUNKLEN) as usize;
+        dst.seek(SeekFrom::Start(from + done))?;
+        dst.read_exact(&mut basebuf[..chunk])?;
+        data.read_exact(&mut databuf[..chunk])?;
+        for (byte, diff) in basebuf[..chunk].iter_mut().zip(databuf[..chunk].iter()) {
+            *byte = combine(*byte, *diff);
+        }
+        dst.seek(SeekFrom::Start(to + done))?;
+        dst.write_all(&basebuf[..chunk])?;
+        done += chunk as u64;
+    }
+    dst.seek(SeekFrom::Start(to + num))?;
+    Ok(())
+}
+
+// an OP_ADD's differences are added modulo 256
+fn add(byte: u8, diff: u8) -> u8 {
+    byte.wrapping_add(diff)
+}
+
+fn xor(byte: u8, diff: u8) -> u8 {
+    byte ^ diff
+}
+
+/*
+ * Reads "len" bytes at "offset" in src, leaving its position unchanged.
+ */
+fn read_at<R: Read + Seek + ?Sized>(src: &mut R, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let pos = src.stream_position()?;
+    let mut buf = vec![0u8; len as usize];
+    src.seek(SeekFrom::Start(offset))?;
+    src.read_exact(&mut buf)?;
+    src.seek(SeekFrom::Start(pos))?;
+    Ok(buf)
+}
+
+/*
+ * Checks that the regions are within a file of "len" bytes, and don't overlap.
+ */
+fn check_regions(regions: &[Region], len: u64) -> Result<()> {
+    let mut end = 0;
+    for region in regions {
+        if region.offset < end || region.offset.checked_add(region.len).is_none_or(|e| e > len) {
+            return Err(VsError::BadRecord(OP_FILTER));
+        }
+        end = region.offset + region.len;
+    }
+    Ok(())
+}
+
+/*
+ * Filters (or unfilters) the regions of file, leaving its position unchanged.
+ */
+fn filter_regions<S: Storage>(file: &mut S, filter: Filter, regions: &[Region], encode: bool) -> Result<()> {
+    for region in regions {
+        let mut code = read_at(file, region.offset, region.len)?;
+        if encode {
+            filter.encode(&mut code, region.offset);
+        } else {
+            filter.decode(&mut code, region.offset);
+        }
+        let pos = file.stream_position()?;
+        file.seek(SeekFrom::Start(region.offset))?;
+        file.write_all(&code)?;
+        file.seek(SeekFrom::Start(pos))?;
+    }
+    Ok(())
+}
+
+fn op_ver(ver: [u8; 3]) -> Result<()> {
+    if ver[0] != 0 {
+        return Err(VsError::UnsupportedVersion(ver));
+    }
+    //println!("OP_VER {:?}.{:?}.{:?}", ver[0], ver[1], ver[2]);
+    Ok(())
+}
+
+fn op_len_a(len: u64, alen: u64)-> Result<()>  {
+    //println!("OP_LEN_A {:?}", len);
+    if len != alen {
+        return Err(VsError::BaseLengthMismatch { expected: len, actual: alen });
+    }
+    Ok(())
+}
+
+fn op_hash_a<A: Read + Seek>(hashbuf: [u8; 32], file_a: &mut A, alen: u64, threads: usize, data: Option<Vec<Range<u64>>>) -> Result<()> {
+    //println!("OP_HASH_A {:02X?}", hashbuf);
+    let hash = match data {
+        Some(data) => hash_sparse_file(file_a, alen, threads, &data)?,
+        None => hash_file_with_threads(file_a, alen, threads)?,
+    };
+    if hash != hashbuf {
+        return Err(VsError::BaseHashMismatch { expected: hashbuf, actual: hash });
+    };
+    Ok(())
+}
+
+fn op_len_b<S: Storage>(len: u64, file: &mut S)-> Result<()>  {
+    file.sync()?;
+    let mut blen = stream_len(file)?;
+
+    //println!("OP_LEN_B {:?}", len);
+    // TODO: this condition should only be be true if file is file_a
+    // Can we check this without wrapping ourselves in knots?
+    if len < blen { // if the file should shrink, we must truncate it
+        file.set_len(len)?;
+        file.sync()?;
+        blen = stream_len(file)?;
+    };
+    if len != blen { // if the file should have grown, it should have already grown due to OP_DIFFs
+        return Err(VsError::TargetLengthMismatch { expected: len, actual: blen });
+    }
+    Ok(())
+}
+
+fn op_hash_b<S: Storage>(hashbuf: [u8; 32], file_b: &mut S, threads: usize) -> Result<()> {
+    file_b.sync()?;
+    let blen = stream_len(file_b)?;
+    //println!("OP_HASH_B {:02X?}", hashbuf);
+    let hash = match file_b.data_extents() {
+        Some(data) => hash_sparse_file(file_b, blen, threads, &data)?,
+        None => hash_file_with_threads(file_b, blen, threads)?,
+    };
+    if hash != hashbuf {
+        return Err(VsError::TargetHashMismatch { expected: hashbuf, actual: hash });
+    };
+    Ok(())
+}
+
+/*
+ * The two ways of applying a delta: modifying file_a in-place, or writing the
+ * result to a separate file_b.
+ */
+trait Mode {
+    type Base: Read + Seek;
+    type Output: Storage;
+    // whether file_a is modified to become file_b
+    const IN_PLACE: bool;
+
+    fn base(&mut self) -> &mut Self::Base;
+    fn output(&mut self) -> &mut Self::Output;
+    // the extents of file_a which are data, if they are known
+    fn base_extents(&mut self) -> Option<Vec<Range<u64>>>;
+
+    // file_b is the same as file_a
+    fn skip(&mut self, count: u64) -> Result<()>;
+    // file_b is different from file_a, the new data is read from "data"
+    fn diff<R: Read>(&mut self, data: &mut R, count: u64) -> Result<()>;
+    // file_b is zero
+    fn hole(&mut self, count: u64) -> Result<()>;
+    // file_b is "count" bytes of "byte"
+    fn fill(&mut self, byte: u8, count: u64) -> Result<()>;
+    // file_b is the same as file_a at "offset"
+    fn copy(&mut self, offset: u64, count: u64) -> Result<()>;
+    // file_b is the same as file_b at "offset", which is before the current position
+    fn copy_b(&mut self, offset: u64, count: u64) -> Result<()>;
+    // file_b is file_a at "offset" plus the differences read from "data"
+    fn add<R: Read>(&mut self, offset: u64, data: &mut R, count: u64) -> Result<()>;
+    // file_b is file_a XOR the data read from "data"
+    fn xor<R: Read>(&mut self, data: &mut R, count: u64) -> Result<()>;
+    // reads "len" bytes of file_a at "offset", to decompress an OP_CDIFF_A
+    fn dictionary(&mut self, offset: u64, len: u64) -> Result<Vec<u8>>;
+    // the delta was made from file_a with these regions filtered
+    fn filter(&mut self, filter: Filter, regions: &[Region]) -> Result<()>;
+}
+
+struct InPlace<'a, T> {
+    file_a: &'a mut T,
+    punch_holes: bool,
+}
+
+struct External<'a, A, B> {
+    file_a: Overlay<&'a mut A>, // filtered, if the delta says so
+    file_b: &'a mut B,
+    sparse: bool,
+    file: fn(&A) -> Option<&File>, // file_a, if it is a File
+}
+
+impl<'a, T: Storage> Mode for InPlace<'a, T> {
+    type Base = T;
+    type Output = T;
+    const IN_PLACE: bool = true;
+
+    fn base(&mut self) -> &mut T {
+        self.file_a
+    }
+
+    fn output(&mut self) -> &mut T {
+        self.file_a
+    }
+
+    fn base_extents(&mut self) -> Option<Vec<Range<u64>>> {
+        self.file_a.data_extents()
+    }
+
+    fn skip(&mut self, count: u64) -> Result<()> {
+        //println!("OP_SKIP {:?}", count);
+        self.file_a.seek(SeekFrom::Current(count as i64))?; // skip, nothing to do
+        Ok(())
+    }
+
+    fn diff<R: Read>(&mut self, data: &mut R, count: u64) -> Result<()> {
+        if self.punch_holes {
+            //println!("OP_DIFF punch_copy_data {:?}", count);
+            punch_copy_data(self.file_a, data, count) // copy data from delta
+        } else {
+            //println!("OP_DIFF {:?}", count);
+            copy_data(self.file_a, data, count) // copy data from delta
+        }
+    }
+
+    fn hole(&mut self, count: u64) -> Result<()> {
+        if self.punch_holes {
+            //println!("OP_HOLE punch_data {:?}", count);
+            punch_data(self.file_a, count) // deallocate the blocks
+        } else {
+            //println!("OP_HOLE zero_data {:?}", count);
+            zero_data(self.file_a, count) // zero any non-zero bytes
+        }
+    }
+
+    fn fill(&mut self, byte: u8, count: u64) -> Result<()> {
+        if byte == 0 {
+            return self.hole(count);
+        }
+        //println!("OP_FILL copy_data {:?} {:?}", byte, count);
+        copy_data(self.file_a, &mut io::repeat(byte), count)
+    }
+
+    fn copy(&mut self, offset: u64, count: u64) -> Result<()> {
+        let pos = self.file_a.stream_position()?;
+        if offset < pos {
+            // this has already been overwritten
+            return Err(VsError::NotInPlace);
+        }
+        //println!("OP_COPY move_data {:?} {:?}", offset, count);
+        move_data(self.file_a, offset, pos, count)
+    }
+
+    fn copy_b(&mut self, offset: u64, count: u64) -> Result<()> {
+        let pos = self.file_a.stream_position()?;
+        if offset >= pos {
+            return Err(VsError::BadRecord(OP_COPY_B));
+        }
+        //println!("OP_COPY_B move_data {:?} {:?}", offset, count);
+        move_data(self.file_a, offset, pos, count)
+    }
+
+    fn add<R: Read>(&mut self, offset: u64, data: &mut R, count: u64) -> Result<()> {
+        let pos = self.file_a.stream_position()?;
+        if offset < pos {
+            // this has already been overwritten
+            return Err(VsError::NotInPlace);
+        }
+        //println!("OP_ADD combine_in_place {:?} {:?}", offset, count);
+        combine_in_place(self.file_a, offset, pos, data, count, add)
+    }
+
+    fn xor<R: Read>(&mut self, data: &mut R, count: u64) -> Result<()> {
+        let pos = self.file_a.stream_position()?;
+        //println!("OP_XOR combine_in_place {:?}", count);
+        combine_in_place(self.file_a, pos, pos, data, count, xor)
+    }
+
+    fn dictionary(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let pos = self.file_a.stream_position()?;
+        if offset < pos {
+            // this has already been overwritten
+            return Err(VsError::NotInPlace);
+        }
+        read_at(self.file_a, offset, len)
+    }
+
+    fn filter(&mut self, filter: Filter, regions: &[Region]) -> Result<()> {
+        filter_regions(self.file_a, filter, regions, true)
+    }
+}
+
+impl<'a, A: Read + Seek, B: Storage> Mode for External<'a, A, B> {
+    type Base = Overlay<&'a mut A>;
+    type Output = B;
+    const IN_PLACE: bool = false;
+
+    fn base(&mut self) -> &mut Overlay<&'a mut A> {
+        &mut self.file_a
+    }
+
+    fn output(&mut self) -> &mut B {
+        self.file_b
+    }
+
+    fn base_extents(&mut self) -> Option<Vec<Range<u64>>> {
+        (self.file)(self.file_a.inner()).and_then(data_extents)
+    }
+
+    fn skip(&mut self, count: u64) -> Result<()> {
+        // filtered data has to be read to be filtered
+        if !self.file_a.is_replaced() {
+            if let Some(file) = (self.file)(self.file_a.inner()) {
+                //println!("OP_SKIP clone_data {:?}", count);
+                return clone_data(self.file_b, file, count, self.sparse); // copy data from file_a
+            }
+        }
+        if self.sparse {
+            //println!("OP_SKIP sparse_copy_data {:?}", count);
+            sparse_copy_data(self.file_b, &mut self.file_a, count) // copy data from file_a
+        } else {
+            //println!("OP_SKIP copy_data {:?}", count);
+            copy_data(self.file_b, &mut self.file_a, count) // copy data from file_a
+        }
+    }
+
+    fn diff<R: Read>(&mut self, data: &mut R, count: u64) -> Result<()> {
+        //println!("OP_DIFF copy_data {:?}", count);
+        self.file_a.seek(SeekFrom::Current(count as i64))?; // skip data in file_a
+        copy_data(self.file_b, data, count) // copy data from delta
+    }
+
+    fn hole(&mut self, count: u64) -> Result<()> {
+        self.file_a.seek(SeekFrom::Current(count as i64))?; // skip data in file_a
+        if self.sparse {
+            //println!("OP_HOLE seek_data {:?}", count);
+            seek_data(self.file_b, count) // leave a hole
+        } else {
+            //println!("OP_HOLE copy_data {:?}", count);
+            copy_data(self.file_b, &mut io::repeat(0), count) // write zeros
+        }
+    }
+
+    fn fill(&mut self, byte: u8, count: u64) -> Result<()> {
+        if byte == 0 {
+            return self.hole(count);
+        }
+        self.file_a.seek(SeekFrom::Current(count as i64))?; // skip data in file_a
+        //println!("OP_FILL copy_data {:?} {:?}", byte, count);
+        copy_data(self.file_b, &mut io::repeat(byte), count)
+    }
+
+    fn copy(&mut self, offset: u64, count: u64) -> Result<()> {
+        // file_a's position tracks file_b's, for subsequent records
+        let pos = self.file_a.stream_position()?;
+        self.file_a.seek(SeekFrom::Start(offset))?;
+        self.skip(count)?; // copy data from file_a
+        self.file_a.seek(SeekFrom::Start(pos + count))?;
+        Ok(())
+    }
+
+    fn copy_b(&mut self, offset: u64, count: u64) -> Result<()> {
+        let pos = self.file_b.stream_position()?;
+        if offset >= pos {
+            return Err(VsError::BadRecord(OP_COPY_B));
+        }
+        //println!("OP_COPY_B move_data {:?} {:?}", offset, count);
+        self.file_a.seek(SeekFrom::Current(count as i64))?; // skip data in file_a
+        move_data(self.file_b, offset, pos, count)
+    }
+
+    fn add<R: Read>(&mut self, offset: u64, data: &mut R, count: u64) -> Result<()> {
+        // file_a's position tracks file_b's, for subsequent records
+        let pos = self.file_a.stream_position()?;
+        self.file_a.seek(SeekFrom::Start(offset))?;
+        //println!("OP_ADD combine_data {:?} {:?}", offset, count);
+        combine_data(self.file_b, &mut self.file_a, data, count, add)?;
+        self.file_a.seek(SeekFrom::Start(pos + count))?;
+        Ok(())
+    }
+
+    fn xor<R: Read>(&mut self, data: &mut R, count: u64) -> Result<()> {
+        //println!("OP_XOR combine_data {:?}", count);
+        combine_data(self.file_b, &mut self.file_a, data, count, xor)
+    }
+
+    fn dictionary(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        read_at(&mut self.file_a, offset, len)
+    }
+
+    fn filter(&mut self, filter: Filter, regions: &[Region]) -> Result<()> {
+        // file_a isn't modified, the filtered code is read in its place
+        for region in regions {
+            let mut code = read_at(self.file_a.inner(), region.offset, region.len)?;
+            filter.encode(&mut code, region.offset);
+            self.file_a.replace(region.offset, code);
+        }
+        Ok(())
+    }
+}
+
+/*
+ * The lengths and hashes of both files of a symmetric delta.  Applying it in
+ * reverse needs OP_LEN_B and OP_HASH_B before the body, but they are at the
+ * end of the delta, so they are found by reading it once beforehand.
+ */
+#[derive(Debug, Clone, Copy)]
+struct Ends {
+    len_a: u64,
+    hash_a: [u8; 32],
+    len_b: u64,
+    hash_b: [u8; 32],
+}
+
+/*
+ * Reads the delta, applying each of its records.  If "reverse" is given, the
+ * delta is applied from file_b to file_a: file_a must match OP_LEN_B and
+ * OP_HASH_B, and the result OP_LEN_A and OP_HASH_A.
+ */
+fn apply<M: Mode, D: Read>(mode: &mut M, delta: D, options: &ApplyOptions, reverse: Option<Ends>) -> Result<()> {
+    let alen = stream_len(mode.base())?;
+    mode.base().seek(SeekFrom::Start(0))?;
+
+    let mut reader = DeltaReader::new(delta)?;
+    let mut unfilter = None; // the filter and regions of file_b, once it is complete
+
+    loop {
+        match reader.next_op()? {
+            Op::Ver(ver) => {
+                op_ver(ver)?;
+            }
+            Op::LenA(len) => {
+                op_len_a(reverse.map_or(len, |ends| ends.len_b), alen)?;
+            }
+            Op::HashA(hash) => {
+                if options.verify || M::IN_PLACE {
+                    let data = mode.base_extents();
+                    op_hash_a(reverse.map_or(hash, |ends| ends.hash_b), mode.base(), alen, options.threads, data)?;
+                }
+            }
+            Op::Filter { filter, regions_a, regions_b } => {
+                check_regions(&regions_a, alen)?;
+                mode.filter(filter, &regions_a)?;
+                unfilter = Some((filter, regions_b));
+            }
+            Op::Skip(count) => {
+                mode.skip(count)?;
+            }
+            Op::Diff(mut payload) => {
+                let count = payload.len();
+                mode.diff(&mut payload, count)?;
+            }
+            Op::DictDiff(compressed) => {
+                if compressed.offset().checked_add(compressed.dict_len()).is_none_or(|end| end > alen) {
+                    return Err(VsError::BadRecord(OP_CDIFF_A));
+                }
+                let dict = mode.dictionary(compressed.offset(), compressed.dict_len())?;
+                let mut payload = compressed.decompress(&dict)?;
+                let count = payload.len();
+                mode.diff(&mut payload, count)?;
+            }
+            Op::Hole(count) => {
+                mode.hole(count)?;
+            }
+            Op::Fill { byte, count } => {
+                mode.fill(byte, count)?;
+            }
+            Op::Copy { offset, count } => {
+                mode.copy(offset, count)?;
+            }
+            Op::CopyB { offset, count } => {
+                mode.copy_b(offset, count)?;
+            }
+            Op::Add { offset, mut data } => {
+                let count = data.len();
+                mode.add(offset, &mut data, count)?;
+            }
+            Op::Xor(mut payload) => {
+                let count = payload.len();
+                mode.xor(&mut payload, count)?;
+            }
+            Op::LenB(len) => {
+                if let Some((filter, regions)) = unfilter.take() {
+                    check_regions(&regions, len)?;
+                    filter_regions(mode.output(), filter, &regions, false)?;
+                }
+                op_len_b(reverse.map_or(len, |ends| ends.len_a), mode.output())?;
+            }
+            Op::HashB(hash) => {
+                if options.verify {
+                    op_hash_b(reverse.map_or(hash, |ends| ends.hash_a), mode.output(), options.threads)?;
+                }
+            }
+            Op::End => {
+                //println!("OP_END");
+                if let Some((filter, regions)) = unfilter.take() {
+                    check_regions(&regions, stream_len(mode.output())?)?;
+                    filter_regions(mode.output(), filter, &regions, false)?;
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Checks, without modifying anything, that the delta can be applied in-place.
+ *
+ * apply_in_place() fails with VsError::NotInPlace part way through such a delta.
+ */
+pub fn check_in_place<D: Read>(delta: D) -> Result<()> {
+    let mut reader = DeltaReader::new(delta)?;
+    let mut pos = 0;
+    loop {
+        match reader.next_op()? {
+            Op::Skip(count) | Op::Hole(count) | Op::Fill { count, .. } | Op::CopyB { count, .. } => pos += count,
+            Op::Diff(payload) | Op::Xor(payload) => pos += payload.len(),
+            Op::DictDiff(compressed) => {
+                if compressed.offset() < pos {
+                    return Err(VsError::NotInPlace);
+                }
+                pos += compressed.len();
+            }
+            Op::Copy { offset, count } => {
+                if offset < pos {
+                    return Err(VsError::NotInPlace);
+                }
+                pos += count;
+            }
+            Op::Add { offset, data } => {
+                if offset < pos {
+                    return Err(VsError::NotInPlace);
+                }
+                pos += data.len();
+            }
+            Op::End => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/*
+ * Reads the whole delta, checking that it is symmetric, i.e. that its body
+ * is only OP_SKIPs and OP_XORs, and that it has the lengths and hashes of
+ * both files, which must be the same length.
+ */
+fn read_ends<D: Read>(delta: D) -> Result<Ends> {
+    let mut reader = DeltaReader::new(delta)?;
+    let (mut len_a, mut hash_a, mut len_b, mut hash_b) = (None, None, None, None);
+    loop {
+        match reader.next_op()? {
+            Op::Ver(ver) => op_ver(ver)?,
+            Op::LenA(len) => len_a = Some(len),
+            Op::HashA(hash) => hash_a = Some(hash),
+            Op::LenB(len) => len_b = Some(len),
+            Op::HashB(hash) => hash_b = Some(hash),
+            Op::Skip(_) | Op::Xor(_) => {}
+            Op::End => break,
+            _ => return Err(VsError::NotSymmetric),
+        }
+    }
+    match (len_a, hash_a, len_b, hash_b) {
+        (Some(len_a), Some(hash_a), Some(len_b), Some(hash_b)) if len_a == len_b => {
+            Ok(Ends { len_a, hash_a, len_b, hash_b })
+        }
+        _ => Err(VsError::NotSymmetric),
+    }
+}
+
+/*
+ * Applies the delta to target, modifying it in-place.
+ */
+pub fn apply_in_place<T: Storage, D: Read>(target: &mut T, delta: D, options: &ApplyOptions) -> Result<()> {
+    apply(&mut InPlace { file_a: target, punch_holes: options.punch_holes }, delta, options, None)
+}
+
+/*
+ * Applies the delta to base, writing the result to output, which should be empty.
+ * base is not modified.
+ */
+pub fn apply_to<A: Read + Seek, D: Read, B: Storage>(base: &mut A, delta: D, output: &mut B, options: &ApplyOptions) -> Result<()> {
+    apply(&mut External { file_a: Overlay::new(base), file_b: output, sparse: options.sparse, file: |_| None }, delta, options, None)
+}
+
+/*
+ * Like apply_to(), but as base is a File, the data which is the same in both
+ * files is copied by the kernel, and shared between them where the
+ * filesystem allows it (e.g. on btrfs or XFS), rather than read and written.
+ */
+pub fn apply_file_to<D: Read, B: Storage>(base: &mut File, delta: D, output: &mut B, options: &ApplyOptions) -> Result<()> {
+    apply(&mut External { file_a: Overlay::new(base), file_b: output, sparse: options.sparse, file: |file| Some(file) }, delta, options, None)
+}
+
+/*
+ * Applies a symmetric delta (see WriteOptions::xor) in reverse, turning its
+ * file_b back into its file_a, in-place.  The delta is read twice.
+ */
+pub fn apply_reverse_in_place<T: Storage, D: Read + Seek>(target: &mut T, mut delta: D, options: &ApplyOptions) -> Result<()> {
+    let start = delta.stream_position()?;
+    let ends = read_ends(&mut delta)?;
+    delta.seek(SeekFrom::Start(start))?;
+    apply(&mut InPlace { file_a: target, punch_holes: options.punch_holes }, delta, options, Some(ends))
+}
+
+/*
+ * Like apply_reverse_in_place(), but the result is written to output, which
+ * should be empty.  base is not modified.
+ */
+pub fn apply_reverse_to<A: Read + Seek, D: Read + Seek, B: Storage>(base: &mut A, mut delta: D, output: &mut B, options: &ApplyOptions) -> Result<()> {
+    let start = delta.stream_position()?;
+    let ends = read_ends(&mut delta)?;
+    delta.seek(SeekFrom::Start(start))?;
+    apply(&mut External { file_a: Overlay::new(base), file_b: output, sparse: options.sparse, file: |_| None }, delta, options, Some(ends))
+}
+
+/*
+ * Like apply_reverse_to(), but as base is a File, the data which is the same
+ * in both files is copied by the kernel, as in apply_file_to().
+ */
+pub fn apply_reverse_file_to<D: Read + Seek, B: Storage>(base: &mut File, mut delta: D, output: &mut B, options: &ApplyOptions) -> Result<()> {
+    let start = delta.stream_position()?;
+    let ends = read_ends(&mut delta)?;
+    delta.seek(SeekFrom::Start(start))?;
+    apply(&mut External { file_a: Overlay::new(base), file_b: output, sparse: options.sparse, file: |file| Some(file) }, delta, options, Some(ends))
+}
@@ -0,0 +1,160 @@
+use std::cmp::{min, Ordering};
+
+/*
+ * A region of file_b which is similar to a region of file_a: the bytes of
+ * file_b are those of file_a plus a difference which is mostly zero.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Add {
+    pub pos: u64,    // in file_b
+    pub offset: u64, // in file_a
+    pub len: u64,
+}
+
+// divsufsort's indexes are i32s
+const MAX_ALEN: usize = i32::MAX as usize;
+
+fn match_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+/*
+ * Finds the longest match for the start of b amongst the suffixes of a,
+ * returning its offset in a and its length.
+ */
+fn search(sa: &[i32], a: &[u8], b: &[u8]) -> (usize, usize) {
+    let (mut lo, mut hi) = (0, sa.len() - 1);
+    while hi - lo >= 2 {
+        let mid = lo + (hi - lo) / 2;
+        let suffix = &a[sa[mid] as usize..];
+        let len = min(suffix.len(), b.len());
+        if suffix[..len].cmp(&b[..len]) == Ordering::Less {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let lo_len = match_len(&a[sa[lo] as usize..], b);
+    let hi_len = match_len(&a[sa[hi] as usize..], b);
+    if lo_len >= hi_len {
+        (sa[lo] as usize, lo_len)
+    } else {
+        (sa[hi] as usize, hi_len)
+    }
+}
+
+/*
+ * Whether b at "pos" is the same as a at "pos + offset".
+ */
+fn same_at(a: &[u8], b: &[u8], pos: usize, offset: isize) -> bool {
+    let i = pos as isize + offset;
+    i >= 0 && (i as usize) < a.len() && a[i as usize] == b[pos]
+}
+
+/*
+ * Finds the regions of b which are approximately the same as regions of a, in
+ * the manner of bsdiff: exact matches are found with a suffix array of a, and
+ * a match is only used when it is more than 8 bytes longer than the run which
+ * continuing with the previous match would give.  Each match is then extended
+ * forwards and backwards for as long as at least half of the bytes are the
+ * same, which covers the small, scattered changes of recompiled code.
+ *
+ * The rest of b is left to be compared with the same positions in a, as is
+ * all of it if a is too large to index.
+ */
+pub(crate) fn find_adds(a: &[u8], b: &[u8]) -> Vec<Add> {
+    let mut adds = Vec::new();
+    if a.is_empty() || a.len() > MAX_ALEN {
+        return adds;
+    }
+    let mut sa = vec![0i32; a.len()];
+    cdivsufsort::sort_in_place(a, &mut sa);
+
+    let (mut scan, mut len, mut pos) = (0usize, 0usize, 0usize);
+    let (mut lastscan, mut lastpos, mut lastoffset) = (0usize, 0usize, 0isize);
+    while scan < b.len() {
+        let mut oldscore = 0isize;
+        scan += len;
+        let mut scsc = scan;
+        while scan < b.len() {
+            let (found, found_len) = search(&sa, a, &b[scan..]);
+            pos = found;
+            len = found_len;
+            while scsc < scan + len {
+                if same_at(a, b, scsc, lastoffset) {
+                    oldscore += 1;
+                }
+                scsc += 1;
+            }
+            if (len as isize == oldscore && len != 0) || len as isize > oldscore + 8 {
+                break;
+            }
+            if same_at(a, b, scan, lastoffset) {
+                oldscore -= 1;
+            }
+            scan += 1;
+        }
+
+        if len as isize != oldscore || scan == b.len() {
+            // extend the previous match forwards
+            let (mut s, mut best, mut lenf) = (0isize, 0isize, 0usize);
+            let mut i = 0;
+            while lastscan + i < scan && lastpos + i < a.len() {
+                if a[lastpos + i] == b[lastscan + i] {
+                    s += 1;
+                }
+                i += 1;
+                if s * 2 - i as isize > best * 2 - lenf as isize {
+                    best = s;
+                    lenf = i;
+                }
+            }
+
+            // and this one backwards
+            let mut lenb = 0;
+            if scan < b.len() {
+                let (mut s, mut best) = (0isize, 0isize);
+                let mut i = 1;
+                while scan >= lastscan + i && pos >= i {
+                    if a[pos - i] == b[scan - i] {
+                        s += 1;
+                    }
+                    if s * 2 - i as isize > best * 2 - lenb as isize {
+                        best = s;
+                        lenb = i;
+                    }
+                    i += 1;
+                }
+            }
+
+            // if they overlap, split the overlap where it suits them best
+            if lastscan + lenf > scan - lenb {
+                let overlap = (lastscan + lenf) - (scan - lenb);
+                let (mut s, mut best, mut lens) = (0isize, 0isize, 0usize);
+                for i in 0..overlap {
+                    if b[lastscan + lenf - overlap + i] == a[lastpos + lenf - overlap + i] {
+                        s += 1;
+                    }
+                    if b[scan - lenb + i] == a[pos - lenb + i] {
+                        s -= 1;
+                    }
+                    if s > best {
+                        best = s;
+                        lens = i + 1;
+                    }
+                }
+                lenf = lenf + lens - overlap;
+                lenb -= lens;
+            }
+
+            if lenf > 0 {
+                adds.push(Add { pos: lastscan as u64, offset: lastpos as u64, len: lenf as u64 });
+            }
+            lastscan = scan - lenb;
+            lastpos = pos - lenb;
+            lastoffset = pos as isize - scan as isize;
+        }
+    }
+
+    adds
+}
@@ -0,0 +1,29 @@
+use structopt::StructOpt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use vsdelta::optimise_delta;
+use anyhow::{Context, Result};
+
+/// Rewrites a delta with the smallest records for its skips and diffs
+#[derive(StructOpt)]
+struct Cli {
+    file_a: String,
+    delta_input: String,
+    delta_output: String,
+}
+
+fn main() -> Result<()> {
+    let args = Cli::from_args();
+
+    let mut file_a = BufReader::new(File::open(&args.file_a)
+                                        .with_context(|| format!("Error opening {}", args.file_a))?);
+    let delta = BufReader::new(File::open(&args.delta_input)
+                                   .with_context(|| format!("Error opening {}", args.delta_input))?);
+    let mut output = BufWriter::new(File::create(&args.delta_output)
+                                        .with_context(|| format!("Error creating {}", args.delta_output))?);
+
+    optimise_delta(&mut file_a, delta, &mut output)?;
+    output.flush()?;
+
+	Result::Ok(())
+}
@@ -1,7 +1,9 @@
 use blake3::Hasher;
-use std::fs::File;
-use std::io::{Result, SeekFrom};
+use blake3::join::RayonJoin;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::io::{Error, ErrorKind, Result, SeekFrom};
 use std::io::prelude::*;
+use std::ops::Range;
 
 pub const CHUNKSIZE: usize = 8;
 pub const CHUNKLEN: u64 = CHUNKSIZE as u64;
@@ -11,40 +13,420 @@ pub const BIGCHUNKLEN: u64 = BIGCHUNKSIZE as u64;
 pub const OP_VER: u8 = 0x00;      // followed by X.Y.Z bytes
 pub const OP_LEN_A: u8 = 0x77;    // followed by length
 pub const OP_HASH_A: u8 = 0xAA; // followed by 32 bytes of hash
+pub const OP_FILTER: u8 = 0xF1;   // followed by filter, then by the count, offsets and lengths of the code regions in file_a, then of those in file_b
 
 pub const OP_SKIP: u8 = 0x55;     // followed by count
 pub const OP_DIFF: u8 = 0xDD;     // followed by count, then by data[count]
 pub const OP_HOLE: u8 = 0x44;     // followed by count
+pub const OP_FILL: u8 = 0xF0;     // followed by 1 byte of value, then by count
+pub const OP_COPY: u8 = 0xCC;     // followed by offset in file_a, then by count
+pub const OP_COPY_B: u8 = 0xCB;   // followed by offset in file_b, then by count
+pub const OP_ADD: u8 = 0xAD;      // followed by offset in file_a, then by an OP_DIFF or OP_CDIFF of differences
+pub const OP_CDIFF: u8 = 0xDC;    // followed by codec, count, compressed length, then by compressed data
+pub const OP_XOR: u8 = 0xE0;      // followed by an OP_DIFF or OP_CDIFF of file_a XOR file_b
+pub const OP_CDIFF_A: u8 = 0xDA;  // followed by codec, offset and length of a dictionary in file_a, count, compressed length, then by compressed data
 
 pub const OP_LEN_B: u8 = 0x88;    // followed by length
 pub const OP_HASH_B: u8 = 0xBB; // followed by 32 bytes of hash
 pub const OP_END: u8 = 0xEE;
 
-/* computes the sha256sum of the file */
-pub fn hash_file(file: &mut File, file_len: u64) -> Result<[u8; 32]> {
-	let mut hasher = Hasher::new();
-
-	file.sync_all().unwrap();
-	file.seek(SeekFrom::Start(0)).unwrap();
-
-	let mut buf = [0u8; BIGCHUNKSIZE];
-	let num_chunks = file_len / BIGCHUNKLEN;
-	for _ in 0..num_chunks {
-		//let pos = file.seek(SeekFrom::Current(0)).unwrap();
-		//println!("pos: {:?}", pos);
-		file.read_exact(&mut buf).unwrap();		
-		hasher.update(&buf);
-	}
-
-	//let pos = file.seek(SeekFrom::Current(0)).unwrap();
-	//println!("pos: {:?}", pos);
-	let remainder = file_len as usize - num_chunks as usize * BIGCHUNKSIZE;
-	//println!("remainder: {:?}", remainder);
-	let mut buf = vec![0u8; remainder];
-	file.read_exact(&mut buf).unwrap();		
-	hasher.update(&buf);
-
-	file.seek(SeekFrom::Start(0)).unwrap();
-	let hash = hasher.finalize();
-	Ok(*hash.as_bytes())
-}
\ No newline at end of file
+pub const CODEC_ZSTD: u8 = 0x01;
+pub const CODEC_LZ4: u8 = 0x02;
+
+pub const FILTER_X86: u8 = 0x01;
+pub const FILTER_ARM64: u8 = 0x02;
+// the most regions an OP_FILTER may have, which is as many sections as an ELF file can have
+pub const MAX_FILTER_REGIONS: u64 = 0xFFFF;
+
+// the largest OP_CDIFF which will be decompressed
+pub const MAX_CDIFF_COUNT: u64 = 16 * 1024 * 1024;
+// the largest dictionary which will be read from file_a for an OP_CDIFF_A
+pub const MAX_DICT_LEN: u64 = 16 * 1024 * 1024;
+
+// from this version onwards, counts and lengths are varints rather than 8 bytes
+pub const VARINT_VERSION: [u8; 3] = [0, 10, 0];
+
+// little endian
+#[inline]
+pub fn u64tou8ale(v: u64) -> [u8; 8] {
+    [
+        v as u8,
+        (v >> 8) as u8,
+        (v >> 16) as u8,
+        (v >> 24) as u8,
+        (v >> 32) as u8,
+        (v >> 40) as u8,
+        (v >> 48) as u8,
+        (v >> 56) as u8,
+    ]
+}
+
+// little endian
+#[inline]
+pub fn u8aletou64(b: [u8; 8]) -> u64 {
+    (b[0] as u64) +
+    ((b[1] as u64) << 8) +
+    ((b[2] as u64) << 16) +
+    ((b[3] as u64) << 24) +
+    ((b[4] as u64) << 32) +
+    ((b[5] as u64) << 40) +
+    ((b[6] as u64) << 48) +
+    ((b[7] as u64) << 56)
+}
+
+/*
+ * Writes v as an unsigned LEB128 varint: 7 bits per byte, least significant
+ * first, with the top bit set on every byte but the last.
+ */
+pub fn write_varint<W: Write + ?Sized>(dst: &mut W, mut v: u64) -> Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (v & 0x7F) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    dst.write_all(&buf[..len])
+}
+
+/*
+ * The number of bytes write_varint() uses for v.
+ */
+pub fn varint_len(mut v: u64) -> u64 {
+    let mut len = 1;
+    while v >= 0x80 {
+        v >>= 7;
+        len += 1;
+    }
+    len
+}
+
+pub fn read_varint<R: Read + ?Sized>(src: &mut R) -> Result<u64> {
+    let mut v = 0u64;
+    let mut byte = [0u8; 1];
+    for shift in (0..64).step_by(7) {
+        src.read_exact(&mut byte)?;
+        let bits = (byte[0] & 0x7F) as u64;
+        if shift == 63 && bits > 1 {
+            break; // overflow
+        }
+        v |= bits << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "Invalid varint."))
+}
+
+pub fn is_zero(buf: &[u8]) -> bool {
+    for byte in buf.iter() {
+        if *byte != 0 {
+            return false;
+        }
+    }
+    true
+}
+
+// files are hashed in reads of this many bytes, each of which is hashed on
+// several threads, if there are several
+const HASH_BUFSIZE: usize = 1024 * 1024;
+// smaller updates are hashed on one thread, as they aren't worth splitting
+const MIN_PARALLEL_HASH_LEN: usize = 128 * 1024;
+
+/*
+ * A blake3 hasher which hashes large updates on several threads.  The hash is
+ * the same for any number of threads.
+ */
+pub(crate) struct ParallelHasher {
+    hasher: Hasher,
+    threads: usize,
+    pool: Option<ThreadPool>, // started by the first large update
+}
+
+impl ParallelHasher {
+    pub fn new(threads: usize) -> ParallelHasher {
+        ParallelHasher { hasher: Hasher::new(), threads, pool: None }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if self.pool.is_none() && self.threads > 1 && data.len() >= MIN_PARALLEL_HASH_LEN {
+            // if it can't be started, everything is hashed on this thread
+            self.pool = ThreadPoolBuilder::new().num_threads(self.threads).build().ok();
+        }
+        let ParallelHasher { hasher, pool, .. } = self;
+        match pool {
+            Some(pool) if data.len() >= MIN_PARALLEL_HASH_LEN => {
+                pool.install(|| hasher.update_with_join::<RayonJoin>(data));
+            }
+            _ => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(&self) -> [u8; 32] {
+        *self.hasher.finalize().as_bytes()
+    }
+}
+
+/* computes the hash of the file */
+pub fn hash_file<R: Read + Seek + ?Sized>(file: &mut R, file_len: u64) -> Result<[u8; 32]> {
+    hash_file_with_threads(file, file_len, 1)
+}
+
+/*
+ * Like hash_file(), but hashes each read on "threads" threads.
+ */
+pub fn hash_file_with_threads<R: Read + Seek + ?Sized>(file: &mut R, file_len: u64, threads: usize) -> Result<[u8; 32]> {
+    let mut hasher = ParallelHasher::new(threads);
+
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut buf = vec![0u8; HASH_BUFSIZE];
+    let mut left = file_len;
+    while left > 0 {
+        let len = left.min(HASH_BUFSIZE as u64) as usize;
+        file.read_exact(&mut buf[..len])?;
+        hasher.update(&buf[..len]);
+        left -= len as u64;
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok(hasher.finalize())
+}
+
+/*
+ * Like hash_file_with_threads(), but "data" gives the extents of the file
+ * which are data, as from data_extents(), and the holes between them are
+ * hashed as zeros without being read.
+ */
+pub fn hash_sparse_file<R: Read + Seek + ?Sized>(file: &mut R, file_len: u64, threads: usize, data: &[Range<u64>]) -> Result<[u8; 32]> {
+    file.seek(SeekFrom::Start(0))?;
+    HashReader::new(file, threads, Some(data.to_vec())).finish(file_len)
+}
+
+/* computes the hash of data which is already in memory, on "threads" threads */
+pub fn hash_data(data: &[u8], threads: usize) -> [u8; 32] {
+    let mut hasher = ParallelHasher::new(threads);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/*
+ * Reads from "inner", hashing the data as it is first read, so that a file
+ * which is read from start to end is hashed without reading it again.  Data
+ * which is read again, or ahead, is only hashed once the reads reach it.
+ *
+ * If the extents of the file which are data are known, the holes between them
+ * are hashed as zeros when reads or finish() pass them, without reading them.
+ */
+pub(crate) struct HashReader<R> {
+    inner: R,
+    hasher: Option<ParallelHasher>, // None if the hash is already known
+    pos: u64,
+    hashed: u64, // how much of the start of the file has been hashed
+    data: Option<Vec<Range<u64>>>,
+    zeros: Vec<u8>, // for hashing holes
+}
+
+impl<R: Read + Seek> HashReader<R> {
+    /*
+     * "inner" must be positioned at its start.  Large reads are hashed on
+     * "threads" threads.  "data" is the extents of the file which are data,
+     * if they are known.
+     */
+    pub fn new(inner: R, threads: usize, data: Option<Vec<Range<u64>>>) -> HashReader<R> {
+        HashReader { inner, hasher: Some(ParallelHasher::new(threads)), pos: 0, hashed: 0, data, zeros: Vec::new() }
+    }
+
+    /*
+     * Like new(), but for a file whose hash is already known, so nothing is
+     * hashed.  finish() only puts the file back to its start, and gives zeros.
+     */
+    pub fn unhashed(inner: R) -> HashReader<R> {
+        HashReader { inner, hasher: None, pos: 0, hashed: 0, data: None, zeros: Vec::new() }
+    }
+
+    /*
+     * Hashes the rest of the file, which is "file_len" bytes long, and returns
+     * its hash.  The file is left positioned at its start.
+     */
+    pub fn finish(&mut self, file_len: u64) -> Result<[u8; 32]> {
+        if self.hasher.is_none() {
+            self.pos = self.inner.seek(SeekFrom::Start(0))?;
+            return Ok([0u8; 32]);
+        }
+        let mut buf = vec![0u8; HASH_BUFSIZE];
+        loop {
+            self.hash_holes(file_len);
+            if self.hashed >= file_len {
+                break;
+            }
+            // read up to the next hole
+            let end = match &self.data {
+                Some(data) => {
+                    let next = data.partition_point(|extent| extent.end <= self.hashed);
+                    data.get(next).map_or(file_len, |extent| extent.end.min(file_len))
+                }
+                None => file_len,
+            };
+            self.inner.seek(SeekFrom::Start(self.hashed))?;
+            while self.hashed < end {
+                let len = (end - self.hashed).min(HASH_BUFSIZE as u64) as usize;
+                self.inner.read_exact(&mut buf[..len])?;
+                self.update(&buf[..len]);
+                self.hashed += len as u64;
+            }
+        }
+        self.pos = self.inner.seek(SeekFrom::Start(0))?;
+        Ok(self.hasher.as_ref().map_or([0u8; 32], ParallelHasher::finalize))
+    }
+
+    /*
+     * Moves past "data", which is known to be the next bytes of the file,
+     * hashing it as if it had been read.
+     */
+    pub fn skip_known(&mut self, data: &[u8]) -> Result<()> {
+        if self.pos > self.hashed {
+            self.hash_holes(self.pos);
+        }
+        if self.pos == self.hashed {
+            self.update(data);
+            self.hashed += data.len() as u64;
+        }
+        self.pos = self.inner.seek(SeekFrom::Current(data.len() as i64))?;
+        Ok(())
+    }
+}
+
+impl<R> HashReader<R> {
+    fn update(&mut self, data: &[u8]) {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(data);
+        }
+    }
+
+    /*
+     * Hashes any hole from where the hashing has reached, up to "to", as
+     * zeros.  Nothing is hashed if the hashing has reached data.
+     */
+    fn hash_holes(&mut self, to: u64) {
+        let data = match &self.data {
+            Some(data) if self.hasher.is_some() => data,
+            _ => return,
+        };
+        let next = data.partition_point(|extent| extent.end <= self.hashed);
+        let end = data.get(next).map_or(to, |extent| extent.start.max(self.hashed).min(to));
+        if self.zeros.is_empty() && end > self.hashed {
+            self.zeros = vec![0u8; HASH_BUFSIZE];
+        }
+        while self.hashed < end {
+            let len = (end - self.hashed).min(HASH_BUFSIZE as u64) as usize;
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(&self.zeros[..len]);
+            }
+            self.hashed += len as u64;
+        }
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos > self.hashed {
+            self.hash_holes(self.pos);
+        }
+        let num = self.inner.read(buf)?;
+        let end = self.pos + num as u64;
+        if self.pos <= self.hashed && end > self.hashed {
+            self.update(&buf[(self.hashed - self.pos) as usize..num]);
+            self.hashed = end;
+        }
+        self.pos = end;
+        Ok(num)
+    }
+}
+
+impl<R: Seek> Seek for HashReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn varints() {
+        for v in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 0x1234_5678, u64::MAX >> 1, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, v).unwrap();
+            assert_eq!(buf.len() as u64, varint_len(v));
+            assert_eq!(read_varint(&mut Cursor::new(&buf)).unwrap(), v);
+        }
+        assert_eq!(varint_len(0x7F), 1);
+        assert_eq!(varint_len(0x80), 2);
+        assert_eq!(varint_len(u64::MAX), 10);
+
+        // too many bits for a u64, and a varint which is cut short
+        let overflow = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02];
+        assert!(read_varint(&mut Cursor::new(&overflow)).is_err());
+        assert!(read_varint(&mut Cursor::new(&[0x80, 0x80])).is_err());
+    }
+
+    #[test]
+    fn hashes_on_threads() {
+        // long enough for several reads, some of which are hashed in parallel
+        let data: Vec<u8> = (0..3 * HASH_BUFSIZE as u32 + 1234).map(|i| (i % 251) as u8).collect();
+        let expected = *blake3::hash(&data).as_bytes();
+        for threads in [1, 2, 5] {
+            assert_eq!(hash_data(&data, threads), expected);
+            let mut file = Cursor::new(&data);
+            assert_eq!(hash_file_with_threads(&mut file, data.len() as u64, threads).unwrap(), expected);
+
+            // reads which go back, and skip ahead, are hashed once, in order
+            let mut reader = HashReader::new(Cursor::new(&data), threads, None);
+            let mut buf = vec![0u8; MIN_PARALLEL_HASH_LEN + 1];
+            reader.read_exact(&mut buf).unwrap();
+            reader.seek(SeekFrom::Start(1000)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            reader.seek(SeekFrom::Start(2 * HASH_BUFSIZE as u64)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(reader.finish(data.len() as u64).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn hashes_holes_as_zeros() {
+        // the "holes" aren't zeros here, so they can't have been read
+        let data: Vec<u8> = (0..HASH_BUFSIZE as u32 * 2 + 5000).map(|i| (i % 13) as u8 + 1).collect();
+        let extents = [Range { start: 100, end: 4096 }, Range { start: HASH_BUFSIZE as u64 + 7, end: 2 * HASH_BUFSIZE as u64 }];
+        let mut zeroed = vec![0u8; data.len()];
+        for extent in extents.iter() {
+            zeroed[extent.start as usize..extent.end as usize].copy_from_slice(&data[extent.start as usize..extent.end as usize]);
+        }
+        let expected = *blake3::hash(&zeroed).as_bytes();
+        for threads in [1, 3] {
+            let mut file = Cursor::new(&data);
+            assert_eq!(hash_sparse_file(&mut file, data.len() as u64, threads, &extents).unwrap(), expected);
+
+            // reads within the data, and skips past known data, hash the
+            // holes before them
+            let mut reader = HashReader::new(Cursor::new(&data), threads, Some(extents.to_vec()));
+            let mut buf = vec![0u8; 1000];
+            reader.seek(SeekFrom::Start(100)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            reader.seek(SeekFrom::Start(HASH_BUFSIZE as u64 + 7)).unwrap();
+            reader.skip_known(&data[HASH_BUFSIZE + 7..HASH_BUFSIZE + 1007]).unwrap();
+            assert_eq!(reader.finish(data.len() as u64).unwrap(), expected);
+        }
+    }
+}
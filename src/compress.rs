@@ -0,0 +1,131 @@
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+use zstd::zstd_safe::{self, CCtx, CParameter, DCtx};
+use crate::common::*;
+
+// zstd's default level, which is fast enough not to slow down creating a delta
+const ZSTD_LEVEL: i32 = 3;
+// the smallest window zstd supports
+const ZSTD_MIN_WINDOWLOG: u32 = 10;
+
+/*
+ * The compression used for the data of an OP_CDIFF or OP_CDIFF_A.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    pub fn from_u8(codec: u8) -> Option<Codec> {
+        match codec {
+            CODEC_ZSTD => Some(Codec::Zstd),
+            CODEC_LZ4 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Codec::Zstd => CODEC_ZSTD,
+            Codec::Lz4 => CODEC_LZ4,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            Codec::Lz4 => Ok(lz4_flex::block::compress(data)),
+        }
+    }
+
+    /*
+     * Like compress(), but "dict" is used as a dictionary of raw content, in
+     * the manner of zstd's --patch-from.  The same dict must be given to
+     * decompress_with_dict().  lz4 only uses the last 64 KiB of it.
+     */
+    pub fn compress_with_dict(self, data: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::Zstd => {
+                // the window must reach back over the whole dictionary
+                let needed = (dict.len() + data.len()).max(1);
+                let window_log = (usize::BITS - (needed - 1).leading_zeros()).max(ZSTD_MIN_WINDOWLOG);
+
+                let mut cctx = CCtx::create();
+                cctx.set_parameter(CParameter::CompressionLevel(ZSTD_LEVEL)).map_err(zstd_error)?;
+                cctx.set_parameter(CParameter::WindowLog(window_log)).map_err(zstd_error)?;
+                // as --patch-from does, otherwise most of a large dictionary is never matched
+                cctx.set_parameter(CParameter::EnableLongDistanceMatching(true)).map_err(zstd_error)?;
+                cctx.ref_prefix(dict).map_err(zstd_error)?;
+                let mut compressed = Vec::with_capacity(zstd_safe::compress_bound(data.len()));
+                cctx.compress2(&mut compressed, data).map_err(zstd_error)?;
+                Ok(compressed)
+            }
+            Codec::Lz4 => Ok(lz4_flex::block::compress_with_dict(data, dict)),
+        }
+    }
+
+    /*
+     * Decompresses data which must decompress to exactly "len" bytes.
+     */
+    pub fn decompress(self, data: &[u8], len: usize) -> Result<Vec<u8>> {
+        let raw = match self {
+            Codec::Zstd => zstd::bulk::decompress(data, len)?,
+            Codec::Lz4 => lz4_flex::block::decompress(data, len)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        };
+        check_len(raw, len)
+    }
+
+    /*
+     * Decompresses data which was compressed by compress_with_dict().
+     */
+    pub fn decompress_with_dict(self, data: &[u8], len: usize, dict: &[u8]) -> Result<Vec<u8>> {
+        let raw = match self {
+            Codec::Zstd => {
+                let mut dctx = DCtx::create();
+                dctx.ref_prefix(dict).map_err(zstd_error)?;
+                let mut raw = Vec::with_capacity(len);
+                dctx.decompress(&mut raw, data).map_err(zstd_error)?;
+                raw
+            }
+            Codec::Lz4 => lz4_flex::block::decompress_with_dict(data, len, dict)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        };
+        check_len(raw, len)
+    }
+}
+
+fn zstd_error(code: zstd_safe::ErrorCode) -> Error {
+    Error::new(ErrorKind::InvalidData, zstd_safe::get_error_name(code))
+}
+
+fn check_len(raw: Vec<u8>, len: usize) -> Result<Vec<u8>> {
+    if raw.len() != len {
+        return Err(Error::new(ErrorKind::InvalidData, "Decompressed data has the wrong length."));
+    }
+    Ok(raw)
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Codec, String> {
+        match s {
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            _ => Err(format!("unknown codec {} (expected zstd or lz4)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        })
+    }
+}
@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/*
+ * Everything which can go wrong while reading or applying a delta.
+ */
+#[derive(Debug)]
+pub enum VsError {
+    // the delta does not start with "vsdelta"
+    BadMagic,
+    // OP_VER names a version of the format which this vsapply does not understand
+    UnsupportedVersion([u8; 3]),
+    // OP_LEN_A does not match the length of file_a
+    BaseLengthMismatch { expected: u64, actual: u64 },
+    // OP_HASH_A does not match the hash of file_a
+    BaseHashMismatch { expected: [u8; 32], actual: [u8; 32] },
+    // OP_LEN_B does not match the length of the result
+    TargetLengthMismatch { expected: u64, actual: u64 },
+    // OP_HASH_B does not match the hash of the result
+    TargetHashMismatch { expected: [u8; 32], actual: [u8; 32] },
+    // the delta ends part way through a record, or before OP_END
+    Truncated,
+    UnknownOpcode(u8),
+    // a record, with this opcode, which cannot be applied
+    BadRecord(u8),
+    // an OP_COPY reads data which has already been overwritten when applying in-place
+    NotInPlace,
+    // the delta has records other than OP_SKIP and OP_XOR, so it cannot be applied in reverse
+    NotSymmetric,
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, VsError>;
+
+fn hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl fmt::Display for VsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VsError::BadMagic =>
+                write!(f, "Not a vsdelta file."),
+            VsError::UnsupportedVersion(ver) =>
+                write!(f, "Incompatible version {}.{}.{}.", ver[0], ver[1], ver[2]),
+            VsError::BaseLengthMismatch { expected, actual } =>
+                write!(f, "This delta expects file_a to be {} bytes long, not {} bytes.", expected, actual),
+            VsError::BaseHashMismatch { expected, actual } =>
+                write!(f, "This delta expects file_a's hash to be {}, not {}.", hex(expected), hex(actual)),
+            VsError::TargetLengthMismatch { expected, actual } =>
+                write!(f, "This delta expects file_b to be {} bytes long, not {} bytes.", expected, actual),
+            VsError::TargetHashMismatch { expected, actual } =>
+                write!(f, "This delta expects file_b's hash to be {}, not {}.", hex(expected), hex(actual)),
+            VsError::Truncated =>
+                write!(f, "The delta is truncated."),
+            VsError::UnknownOpcode(opcode) =>
+                write!(f, "Unknown opcode 0x{:02X}.", opcode),
+            VsError::BadRecord(opcode) =>
+                write!(f, "Invalid record with opcode 0x{:02X}.", opcode),
+            VsError::NotInPlace =>
+                write!(f, "This delta cannot be applied in-place, it must be applied to a separate file_b."),
+            VsError::NotSymmetric =>
+                write!(f, "This delta cannot be applied in reverse, it must be made with vsdelta --xor."),
+            VsError::Io(e) =>
+                write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for VsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VsError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VsError {
+    fn from(e: io::Error) -> VsError {
+        // a VsError which has travelled through an io::Read, e.g. a Payload
+        if e.get_ref().is_some_and(|inner| inner.is::<VsError>()) {
+            return *e.into_inner().unwrap().downcast::<VsError>().unwrap();
+        }
+        VsError::Io(e)
+    }
+}
+
+impl From<VsError> for io::Error {
+    fn from(e: VsError) -> io::Error {
+        match e {
+            VsError::Io(e) => e,
+            VsError::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::cmp::{max, min};
+use std::convert::TryInto;
+use std::io::{Read, Result, Seek, SeekFrom};
+use crate::common::*;
+
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const SHT_NOBITS: u32 = 8;
+const SHF_EXECINSTR: u64 = 0x4;
+
+/*
+ * A BCJ ("branch/call/jump") filter, which converts the relative targets of
+ * calls and branches in machine code to absolute ones, so that they stay the
+ * same when the code around them moves.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    X86,   // x86-64 CALL and JMP rel32
+    Arm64, // ARM64 BL
+}
+
+/*
+ * A region of a file which contains machine code.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub offset: u64,
+    pub len: u64,
+}
+
+fn u16_at(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+/*
+ * Finds the filter for a little-endian ELF64 file, and the sections of it
+ * which contain code.  Sections which aren't within the file, or which
+ * overlap an earlier one, are ignored.
+ */
+pub fn detect(data: &[u8]) -> Option<(Filter, Vec<Region>)> {
+    if data.get(0..4)? != ELF_MAGIC || data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+        return None;
+    }
+    let filter = match u16_at(data, 0x12)? {
+        EM_X86_64 => Filter::X86,
+        EM_AARCH64 => Filter::Arm64,
+        _ => return None,
+    };
+
+    let shoff = u64_at(data, 0x28)?;
+    let shentsize = u16_at(data, 0x3A)? as u64;
+    let shnum = u16_at(data, 0x3C)? as u64;
+    let mut regions = Vec::new();
+    for i in 0..shnum {
+        let header = match shoff.checked_add(i * shentsize) {
+            Some(header) if header + 0x28 <= data.len() as u64 => header as usize,
+            _ => break,
+        };
+        let flags = u64_at(data, header + 0x08)?;
+        let offset = u64_at(data, header + 0x18)?;
+        let len = u64_at(data, header + 0x20)?;
+        if u32_at(data, header + 0x04)? == SHT_NOBITS || flags & SHF_EXECINSTR == 0 || len == 0 {
+            continue;
+        }
+        if offset.checked_add(len).is_some_and(|end| end <= data.len() as u64) {
+            regions.push(Region { offset, len });
+        }
+    }
+
+    regions.sort_by_key(|region| region.offset);
+    let mut end = 0;
+    regions.retain(|region| {
+        let keep = region.offset >= end;
+        if keep {
+            end = region.offset + region.len;
+        }
+        keep
+    });
+    Some((filter, regions))
+}
+
+impl Filter {
+    pub fn from_u8(filter: u8) -> Option<Filter> {
+        match filter {
+            FILTER_X86 => Some(Filter::X86),
+            FILTER_ARM64 => Some(Filter::Arm64),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Filter::X86 => FILTER_X86,
+            Filter::Arm64 => FILTER_ARM64,
+        }
+    }
+
+    /*
+     * Filters code which is at "offset" in its file.
+     */
+    pub fn encode(self, code: &mut [u8], offset: u64) {
+        self.convert(code, offset, true)
+    }
+
+    /*
+     * Reverses encode().
+     */
+    pub fn decode(self, code: &mut [u8], offset: u64) {
+        self.convert(code, offset, false)
+    }
+
+    fn convert(self, code: &mut [u8], offset: u64, encode: bool) {
+        match self {
+            Filter::X86 => {
+                // A CALL (E8) or JMP (E9) is converted if its target is within
+                // 16 MiB, i.e. its top byte is 0x00 or 0xFF.  The conversion is
+                // modulo 2^25, which keeps it so.  The 4 bytes after every E8
+                // or E9 are skipped, whether or not they were converted, so
+                // that decoding looks at the same bytes as encoding did.
+                let mut i = 0;
+                while i + 5 <= code.len() {
+                    if code[i] != 0xE8 && code[i] != 0xE9 {
+                        i += 1;
+                        continue;
+                    }
+                    if code[i + 4] == 0x00 || code[i + 4] == 0xFF {
+                        let rel = i32::from_le_bytes(code[i + 1..i + 5].try_into().unwrap());
+                        let next = (offset + i as u64 + 5) as i32;
+                        let target = if encode { rel.wrapping_add(next) } else { rel.wrapping_sub(next) };
+                        let target = (target << 7) >> 7;
+                        code[i + 1..i + 5].copy_from_slice(&target.to_le_bytes());
+                    }
+                    i += 5;
+                }
+            }
+            Filter::Arm64 => {
+                // a BL's target is a 26 bit count of instructions
+                let mut i = ((4 - offset % 4) % 4) as usize;
+                while i + 4 <= code.len() {
+                    let word = u32::from_le_bytes(code[i..i + 4].try_into().unwrap());
+                    if word & 0xFC00_0000 == 0x9400_0000 {
+                        let here = ((offset + i as u64) >> 2) as u32;
+                        let imm = word & 0x03FF_FFFF;
+                        let imm = if encode { imm.wrapping_add(here) } else { imm.wrapping_sub(here) };
+                        code[i..i + 4].copy_from_slice(&(0x9400_0000 | (imm & 0x03FF_FFFF)).to_le_bytes());
+                    }
+                    i += 4;
+                }
+            }
+        }
+    }
+}
+
+/*
+ * Reads from "inner", but with the given regions replaced by other data, so
+ * that a file can be filtered without modifying it.
+ */
+pub(crate) struct Overlay<R> {
+    inner: R,
+    regions: Vec<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> Overlay<R> {
+    pub fn new(inner: R) -> Overlay<R> {
+        Overlay { inner, regions: Vec::new() }
+    }
+
+    pub fn inner(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn replace(&mut self, offset: u64, data: Vec<u8>) {
+        self.regions.push((offset, data));
+    }
+
+    // whether anything reads differently from "inner"
+    pub fn is_replaced(&self) -> bool {
+        !self.regions.is_empty()
+    }
+}
+
+impl<R: Read + Seek> Read for Overlay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.regions.is_empty() {
+            return self.inner.read(buf);
+        }
+        let pos = self.inner.stream_position()?;
+        let num = self.inner.read(buf)?;
+        let end = pos + num as u64;
+        for (offset, data) in self.regions.iter() {
+            let from = max(pos, *offset);
+            let to = min(end, offset + data.len() as u64);
+            if from < to {
+                buf[(from - pos) as usize..(to - pos) as usize]
+                    .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            }
+        }
+        Ok(num)
+    }
+}
+
+impl<R: Seek> Seek for Overlay<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_reverses_encode() {
+        let mut seed = 0x9E3779B97F4A7C15u64;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for _ in 0..2000 {
+            let len = (random() % 5000) as usize;
+            // plenty of opcodes, and of targets which are near enough to convert
+            let code: Vec<u8> = (0..len).map(|_| match random() % 6 {
+                0 => 0xE8,
+                1 => 0x00,
+                2 => 0xFF,
+                3 => 0x94,
+                _ => random() as u8,
+            }).collect();
+            let offset = random() % (1 << 40);
+            for filter in [Filter::X86, Filter::Arm64] {
+                let mut filtered = code.clone();
+                filter.encode(&mut filtered, offset);
+                filter.decode(&mut filtered, offset);
+                assert!(filtered == code, "{:?} at {}", filter, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn moved_calls_encode_the_same() {
+        // a CALL at 0x1000, and the same call moved to 0x1400, to 0x5000
+        let mut moved = [0xE8, 0xFB, 0x3B, 0x00, 0x00];
+        let mut call = [0xE8, 0xFB, 0x3F, 0x00, 0x00];
+        Filter::X86.encode(&mut call, 0x1000);
+        Filter::X86.encode(&mut moved, 0x1400);
+        assert_eq!(call, moved);
+
+        // a BL at 0x1000, and the same call moved to 0x1400, to 0x5000
+        let mut call = 0x94001000u32.to_le_bytes();
+        let mut moved = 0x94000F00u32.to_le_bytes();
+        Filter::Arm64.encode(&mut call, 0x1000);
+        Filter::Arm64.encode(&mut moved, 0x1400);
+        assert_eq!(call, moved);
+    }
+}
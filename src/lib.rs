@@ -1 +1,29 @@
+pub mod apply;
+mod approx;
 pub mod common;
+pub mod compress;
+pub mod error;
+pub mod filter;
+pub mod mmap;
+mod moves;
+pub mod optimise;
+pub mod reader;
+pub mod sparse;
+pub mod storage;
+pub mod writer;
+
+pub use apply::{apply_file_to, apply_in_place, apply_reverse_file_to, apply_reverse_in_place, apply_reverse_to, apply_to, check_in_place, ApplyOptions};
+pub use compress::Codec;
+pub use error::VsError;
+pub use filter::{Filter, Region};
+pub use mmap::map_file;
+pub use optimise::optimise_delta;
+pub use reader::{DeltaReader, DictPayload, Op, Payload};
+pub use sparse::{data_extents, shared_extents};
+pub use storage::Storage;
+pub use writer::{DeltaWriter, WriteOptions};
+
+pub mod built_info {
+    // The file has been placed there by the build script.
+    include!(concat!(env!("OUT_DIR"), "/built.rs"));
+}
@@ -0,0 +1,30 @@
+use std::fs::File;
+use memmap2::Mmap;
+
+/**
+ * Memory-maps a regular file, so that it can be compared without being read
+ * into buffers.  Devices and pipes, and anything else which can't be mapped,
+ * give None, and should be read instead.
+ *
+ * # Safety
+ *
+ * The file must not be modified or truncated, by this process or any other,
+ * while the mapping is alive.  The mapped data would change under anything
+ * borrowing it, and reading past a truncated end raises SIGBUS.
+ */
+pub unsafe fn map_file(file: &File) -> Option<Mmap> {
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        // an empty mapping isn't allowed, and there is nothing to read anyway
+        return None;
+    }
+    // the caller keeps the file from being modified, see above
+    let map = unsafe { Mmap::map(file) }.ok()?;
+    if map.len() as u64 != metadata.len() {
+        return None;
+    }
+    // the files are compared from start to end
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Some(map)
+}
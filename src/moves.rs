@@ -0,0 +1,203 @@
+use std::cmp::min;
+use std::collections::HashMap;
+
+// the smallest block of file_a which is indexed
+const MIN_BLOCKSIZE: usize = 16;
+// the largest block of file_a which is indexed
+const MAX_BLOCKSIZE: usize = 64 * 1024;
+// how many blocks of file_a with the same weak hash are remembered
+const MAX_CANDIDATES: usize = 8;
+
+/*
+ * A region of file_b which is a copy of a region of file_a, at a different
+ * position, or of an earlier region of file_b itself.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Move {
+    pub pos: u64,    // in file_b
+    pub offset: u64, // in file_a, or in file_b if from_b is set
+    pub len: u64,
+    pub from_b: bool,
+}
+
+/*
+ * rsync's weak rolling checksum, over a window of "len" bytes.
+ */
+struct Rolling {
+    s1: u32,
+    s2: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let mut rolling = Rolling { s1: 0, s2: 0, len: window.len() as u32 };
+        for &byte in window {
+            rolling.s1 = rolling.s1.wrapping_add(byte as u32);
+            rolling.s2 = rolling.s2.wrapping_add(rolling.s1);
+        }
+        rolling
+    }
+
+    /*
+     * Moves the window forwards by one byte.
+     */
+    fn roll(&mut self, out: u8, inb: u8) {
+        self.s1 = self.s1.wrapping_sub(out as u32).wrapping_add(inb as u32);
+        self.s2 = self.s2.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.s1);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.s1 & 0xFFFF) | (self.s2 << 16)
+    }
+}
+
+/*
+ * Chooses the block size for a file_a of "alen" bytes.  This keeps the number
+ * of blocks in the index to about the square root of the file's length.
+ */
+fn block_size(alen: usize) -> usize {
+    let mut size = MIN_BLOCKSIZE;
+    while size < MAX_BLOCKSIZE && size * size < alen {
+        size *= 2;
+    }
+    size
+}
+
+fn add_block(index: &mut HashMap<u32, Vec<usize>>, data: &[u8], offset: usize, block: usize) {
+    let candidates = index.entry(Rolling::new(&data[offset..offset + block]).digest()).or_default();
+    if candidates.len() < MAX_CANDIDATES {
+        candidates.push(offset);
+    }
+}
+
+/*
+ * Returns the length of the match between src at "offset" and b at "pos", which
+ * is at least "block" long if the blocks are the same, otherwise zero.
+ */
+fn match_len(src: &[u8], offset: usize, b: &[u8], pos: usize, block: usize) -> usize {
+    if src[offset..offset + block] != b[pos..pos + block] {
+        return 0;
+    }
+    let mut len = block;
+    while pos + len < b.len() && offset + len < src.len() && src[offset + len] == b[pos + len] {
+        len += 1;
+    }
+    len
+}
+
+/*
+ * Finds the regions of b which can be copied from elsewhere in a, or from
+ * earlier in b, in the manner of rsync: every block of a is indexed by its weak
+ * rolling checksum, and a window is rolled over b, one byte at a time, looking
+ * for those blocks.  Blocks of b are added to the index once the window has
+ * passed them.  Matches are then extended, byte-by-byte, in both directions.
+ * Matches within b may overlap the window, as in LZ77.
+ *
+ * Data which is at the same position in a and b is not reported, as it will
+ * become an OP_SKIP.  If "in_place" is set, only regions of a which are not
+ * before the corresponding position in b are used, so that the delta can be
+ * applied in-place.
+ */
+pub(crate) fn find_moves(a: &[u8], b: &[u8], in_place: bool) -> Vec<Move> {
+    let mut moves = Vec::new();
+    let block = block_size(a.len());
+    if b.len() < block {
+        return moves;
+    }
+
+    let mut index_a: HashMap<u32, Vec<usize>> = HashMap::new();
+    if a.len() >= block {
+        for offset in (0..=(a.len() - block)).step_by(block) {
+            add_block(&mut index_a, a, offset, block);
+        }
+    }
+    let mut index_b: HashMap<u32, Vec<usize>> = HashMap::new();
+    let mut indexed_b = 0; // the end of the blocks of b which have been indexed
+
+    let mut pos = 0;      // the start of the window in b
+    let mut unmatched = 0; // the end of the last match, or aligned data
+    let mut rolling = Rolling::new(&b[0..block]);
+    // the data of b which has been looked at for zeros, and the last byte of it
+    // which isn't zero, so that each window doesn't have to be looked at again
+    let mut scanned = 0;
+    let mut last_non_zero: Option<usize> = None;
+    while pos + block <= b.len() {
+        let window = &b[pos..pos + block];
+
+        // data which is already in the same place is left alone
+        if pos + block <= a.len() && &a[pos..pos + block] == window {
+            let mut end = pos + block;
+            while end < min(a.len(), b.len()) && a[end] == b[end] {
+                end += 1;
+            }
+            pos = end;
+            unmatched = pos;
+            if pos + block <= b.len() {
+                rolling = Rolling::new(&b[pos..pos + block]);
+            }
+            continue;
+        }
+
+        while indexed_b + block <= pos {
+            add_block(&mut index_b, b, indexed_b, block);
+            indexed_b += block;
+        }
+
+        // find the longest match amongst the blocks with the same checksum,
+        // but runs of zeros are better as OP_HOLEs
+        let mut best: Option<(usize, usize, bool)> = None; // offset, len, from_b
+        if let Some(last) = b[scanned..pos + block].iter().rposition(|&byte| byte != 0) {
+            last_non_zero = Some(scanned + last);
+        }
+        scanned = pos + block;
+        if last_non_zero.is_some_and(|last| last >= pos) {
+            let digest = rolling.digest();
+            for &offset in index_a.get(&digest).into_iter().flatten() {
+                if in_place && offset < pos {
+                    continue;
+                }
+                let len = match_len(a, offset, b, pos, block);
+                if len > 0 && best.is_none_or(|(_, best_len, _)| len > best_len) {
+                    best = Some((offset, len, false));
+                }
+            }
+            for &offset in index_b.get(&digest).into_iter().flatten() {
+                let len = match_len(b, offset, b, pos, block);
+                if len > 0 && best.is_none_or(|(_, best_len, _)| len > best_len) {
+                    best = Some((offset, len, true));
+                }
+            }
+        }
+
+        match best {
+            Some((offset, len, from_b)) => {
+                // extend the match backwards, into data which hasn't been matched
+                let src = if from_b { b } else { a };
+                let mut back = 0;
+                while pos - back > unmatched && offset - back > 0 && src[offset - back - 1] == b[pos - back - 1] {
+                    back += 1;
+                }
+                moves.push(Move {
+                    pos: (pos - back) as u64,
+                    offset: (offset - back) as u64,
+                    len: (len + back) as u64,
+                    from_b,
+                });
+                pos += len;
+                unmatched = pos;
+                if pos + block <= b.len() {
+                    rolling = Rolling::new(&b[pos..pos + block]);
+                }
+            },
+            None => {
+                if pos + block < b.len() {
+                    rolling.roll(b[pos], b[pos + block]);
+                }
+                pos += 1;
+            }
+        }
+    }
+
+    moves
+}
@@ -0,0 +1,516 @@
+use std::collections::VecDeque;
+use std::io::{self, SeekFrom};
+use std::io::prelude::*;
+use crate::common::*;
+use crate::error::{Result, VsError};
+use crate::filter::Overlay;
+use crate::reader::{DeltaReader, Op};
+use crate::writer::*;
+
+// a record this long is never worth writing as part of an OP_DIFF instead: its
+// own record is at most a few bytes, and the OP_DIFF header which it could
+// save is at most 12
+const MAX_ABSORB_LEN: u64 = 64;
+// the longest varint, of a u64
+const MAX_VARINT_LEN: usize = 10;
+// the most OP_DIFF or OP_XOR data which optimise_delta() holds in memory, a
+// longer record is written as it is
+const MAX_RUN_DATA: u64 = 1024 * 1024;
+// the most segments, and data, which optimise_delta() holds for plan() at
+// once, so that its memory doesn't grow with the size of the delta
+const MAX_RUN_SEGMENTS: usize = 64 * 1024;
+const MAX_RUN_HELD: usize = 16 * 1024 * 1024;
+
+/*
+ * The length and kind of a record which describes part of file_b.  The data
+ * of a Diff is the data of file_b, or the XOR of file_a and file_b in a
+ * symmetric delta.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Segment {
+    Skip(u64),
+    Diff(u64),
+    Hole(u64),
+    Fill(u8, u64),
+}
+
+/*
+ * How plan() writes each segment: as its own record, or as part of an OP_DIFF
+ * (or OP_XOR) of this many consecutive segments.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Plan {
+    Keep,
+    Diff(usize),
+}
+
+impl Segment {
+    pub fn len(self) -> u64 {
+        match self {
+            Segment::Skip(len) | Segment::Diff(len) | Segment::Hole(len) | Segment::Fill(_, len) => len,
+        }
+    }
+
+    /*
+     * The size of the segment's own record, if it can have one.
+     */
+    fn cost(self) -> Option<u64> {
+        match self {
+            Segment::Skip(len) | Segment::Hole(len) => Some(1 + varint_len(len)),
+            Segment::Fill(_, len) => Some(2 + varint_len(len)),
+            Segment::Diff(_) => None,
+        }
+    }
+
+    /*
+     * Whether the segment might be written as part of an OP_DIFF.  In a
+     * symmetric delta, skipped data XORs to zeros, but holes and fills lose
+     * what file_a was.
+     */
+    pub fn absorbable(self, xor: bool) -> bool {
+        match self {
+            Segment::Diff(_) => true,
+            Segment::Skip(len) => len < MAX_ABSORB_LEN,
+            Segment::Hole(len) | Segment::Fill(_, len) => !xor && len < MAX_ABSORB_LEN,
+        }
+    }
+}
+
+/*
+ * Chooses which segments to write as their own records, and which to join,
+ * with their neighbours, into OP_DIFFs (or OP_XORs, if "xor" is set), so that
+ * the records are as small as possible.  A short OP_SKIP between two OP_DIFFs
+ * is smaller as part of one OP_DIFF, as that saves its record and an OP_DIFF
+ * header.
+ *
+ * This is a dynamic program over the segments: the cost of the first k is
+ * the cheaper of the cost of the first k - 1 plus the kth's own record, or of
+ * the first j - 1 plus an OP_DIFF of the jth to the kth.  The length of that
+ * OP_DIFF's varint depends on j, so the best j is found separately for each
+ * length of varint, each of which is a sliding window over j.
+ */
+pub(crate) fn plan(segments: &[Segment], xor: bool) -> Vec<Plan> {
+    let n = segments.len();
+    let header = if xor { 2 } else { 1 }; // OP_XOR is followed by an OP_DIFF
+    let mut sum = vec![0u64; n + 1];  // of the lengths of the first k segments
+    let mut best = vec![0u64; n + 1]; // the cost of the first k segments
+    let mut start = vec![0usize; n + 1]; // the first segment of the OP_DIFF which ends with the kth, if any
+
+    // for each length of varint, the segments which could start an OP_DIFF
+    // of that length ending with the kth, in order, with the cheapest first
+    let mut windows: Vec<VecDeque<usize>> = vec![VecDeque::new(); MAX_VARINT_LEN];
+    let mut next = [1usize; MAX_VARINT_LEN]; // the next segment to enter each window
+    let key = |best: &[u64], sum: &[u64], j: usize| best[j - 1] as i64 - sum[j - 1] as i64;
+
+    for k in 1..=n {
+        let segment = segments[k - 1];
+        sum[k] = sum[k - 1] + segment.len();
+        best[k] = segment.cost().map_or(u64::MAX, |cost| best[k - 1] + cost);
+        if !segment.absorbable(xor) {
+            // no OP_DIFF can reach across this
+            for (window, next) in windows.iter_mut().zip(next.iter_mut()) {
+                window.clear();
+                *next = k + 1;
+            }
+            continue;
+        }
+
+        for (c, (window, next)) in windows.iter_mut().zip(next.iter_mut()).enumerate() {
+            // the lengths which need a varint of c + 1 bytes
+            let min_len = if c == 0 { 0 } else { 1u64 << (7 * c) };
+            let max_len = 1u64.checked_shl(7 * (c as u32 + 1));
+            while *next <= k && sum[k] - sum[*next - 1] >= min_len {
+                let j = *next;
+                while window.back().is_some_and(|&i| key(&best, &sum, i) >= key(&best, &sum, j)) {
+                    window.pop_back();
+                }
+                window.push_back(j);
+                *next += 1;
+            }
+            while window.front().is_some_and(|&j| max_len.is_some_and(|max| sum[k] - sum[j - 1] >= max)) {
+                window.pop_front();
+            }
+            if let Some(&j) = window.front() {
+                let cost = best[j - 1] + header + c as u64 + 1 + (sum[k] - sum[j - 1]);
+                if cost < best[k] {
+                    best[k] = cost;
+                    start[k] = j;
+                }
+            }
+        }
+    }
+
+    let mut plan = Vec::new();
+    let mut k = n;
+    while k > 0 {
+        if start[k] == 0 {
+            plan.push(Plan::Keep);
+            k -= 1;
+        } else {
+            plan.push(Plan::Diff(k - start[k] + 1));
+            k = start[k] - 1;
+        }
+    }
+    plan.reverse();
+    plan
+}
+
+/*
+ * The segments of an existing delta which have yet to be planned, and their
+ * data: that of each Diff, and of each absorbable Skip, Hole and Fill.
+ */
+struct Run {
+    segments: Vec<Segment>,
+    data: Vec<u8>,
+    xor: Option<bool>, // whether the Diffs are OP_XORs, once there are any
+    symmetric: bool,   // whether the delta has had any OP_XORs
+}
+
+impl Run {
+    fn xor(&self) -> bool {
+        self.xor.unwrap_or(self.symmetric)
+    }
+
+    /*
+     * Whether the segment's data is held, which it is if it might be written
+     * as part of an OP_DIFF.
+     */
+    fn has_data(segment: Segment) -> bool {
+        segment.absorbable(false)
+    }
+
+    /*
+     * Writes the records for all of the segments.
+     */
+    fn flush<W: Write>(&mut self, output: &mut W) -> Result<()> {
+        let xor = self.xor();
+        let plan = plan(&self.segments, xor);
+        let mut segments = self.segments.iter();
+        let mut data = &self.data[..]; // the data of the segments which haven't been written
+        for step in plan {
+            match step {
+                Plan::Keep => {
+                    let segment = *segments.next().unwrap();
+                    if Run::has_data(segment) {
+                        data = &data[segment.len() as usize..];
+                    }
+                    match segment {
+                        Segment::Skip(count) => write_op_skip(output, count)?,
+                        Segment::Hole(count) => write_op_hole(output, count)?,
+                        Segment::Fill(byte, count) => write_op_fill(output, byte, count)?,
+                        Segment::Diff(_) => unreachable!(),
+                    }
+                }
+                Plan::Diff(num) => {
+                    let mut joined = Vec::new();
+                    for &segment in segments.by_ref().take(num) {
+                        let (bytes, rest) = data.split_at(segment.len() as usize);
+                        match segment {
+                            // skipped data XORs to zeros
+                            Segment::Skip(len) if xor => joined.resize(joined.len() + len as usize, 0),
+                            _ => joined.extend_from_slice(bytes),
+                        }
+                        data = rest;
+                    }
+                    if xor {
+                        output.write_all(&[OP_XOR])?;
+                    }
+                    write_op_diff(output, &joined)?;
+                }
+            }
+        }
+        self.segments.clear();
+        self.data.clear();
+        self.xor = None;
+        Ok(())
+    }
+
+    /*
+     * Writes the records for all of the segments, if as many are held as
+     * are held at once.  The plan is then split there, which costs at most an
+     * OP_DIFF header.
+     */
+    fn flush_if_full<W: Write>(&mut self, output: &mut W) -> Result<()> {
+        if self.segments.len() >= MAX_RUN_SEGMENTS || self.data.len() >= MAX_RUN_HELD {
+            self.flush(output)?;
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Writes an OP_DIFF (or OP_XOR) of "count" bytes read from data, without
+ * holding it in memory.
+ */
+fn stream_diff<W: Write, R: Read>(output: &mut W, xor: bool, data: &mut R, count: u64) -> Result<()> {
+    if xor {
+        output.write_all(&[OP_XOR])?;
+    }
+    output.write_all(&[OP_DIFF])?;
+    write_varint(output, count)?;
+    if io::copy(&mut data.take(count), output)? != count {
+        return Err(VsError::Truncated);
+    }
+    Ok(())
+}
+
+/*
+ * Rewrites an existing delta, with its OP_SKIPs, OP_DIFFs, OP_HOLEs, OP_FILLs
+ * and OP_XORs chosen by plan(), which can make it smaller.  This needs file_a,
+ * for the data of OP_SKIPs which become part of an OP_DIFF.  Compressed
+ * records are compressed again, with the same codec and dictionary, and all
+ * other records are written as they are.  The delta is written with the
+ * current version.
+ */
+pub fn optimise_delta<A: Read + Seek, D: Read, W: Write>(file_a: &mut A, delta: D, output: &mut W) -> Result<()> {
+    let mut file_a = Overlay::new(file_a); // filtered, if the delta says so
+    let mut reader = DeltaReader::new(delta)?;
+    let mut run = Run { segments: Vec::new(), data: Vec::new(), xor: None, symmetric: false };
+    let mut pos = 0; // in file_b
+
+    write_magic(output)?;
+    loop {
+        match reader.next_op()? {
+            Op::Ver(_) => {
+                write_op_ver(output, version())?;
+            }
+            Op::LenA(len) => {
+                write_op_len_a(output, len)?;
+            }
+            Op::HashA(hash) => {
+                write_op_hash_a(output, hash)?;
+            }
+            Op::Filter { filter, regions_a, regions_b } => {
+                for region in regions_a.iter() {
+                    let mut code = vec![0u8; region.len as usize];
+                    file_a.inner().seek(SeekFrom::Start(region.offset))?;
+                    file_a.inner().read_exact(&mut code)?;
+                    filter.encode(&mut code, region.offset);
+                    file_a.replace(region.offset, code);
+                }
+                write_op_filter(output, filter, &regions_a, &regions_b)?;
+            }
+            Op::Skip(count) => {
+                let segment = Segment::Skip(count);
+                if Run::has_data(segment) {
+                    let start = run.data.len();
+                    run.data.resize(start + count as usize, 0);
+                    file_a.seek(SeekFrom::Start(pos))?;
+                    file_a.read_exact(&mut run.data[start..])?;
+                }
+                run.segments.push(segment);
+                if !segment.absorbable(run.xor()) {
+                    run.flush(output)?;
+                }
+                pos += count;
+            }
+            Op::Hole(count) | Op::Fill { byte: 0, count } => {
+                let segment = Segment::Hole(count);
+                if Run::has_data(segment) {
+                    run.data.resize(run.data.len() + count as usize, 0);
+                }
+                run.segments.push(segment);
+                if !segment.absorbable(run.xor()) {
+                    run.flush(output)?;
+                }
+                pos += count;
+            }
+            Op::Fill { byte, count } => {
+                let segment = Segment::Fill(byte, count);
+                if Run::has_data(segment) {
+                    run.data.resize(run.data.len() + count as usize, byte);
+                }
+                run.segments.push(segment);
+                if !segment.absorbable(run.xor()) {
+                    run.flush(output)?;
+                }
+                pos += count;
+            }
+            Op::Diff(mut payload) => {
+                let count = payload.len();
+                if let Some(codec) = payload.codec() {
+                    run.flush(output)?;
+                    let mut data = Vec::with_capacity(count as usize);
+                    payload.read_to_end(&mut data)?;
+                    write_op_cdiff(output, codec, &data)?;
+                } else if count > MAX_RUN_DATA {
+                    run.flush(output)?;
+                    stream_diff(output, false, &mut payload, count)?;
+                } else {
+                    if run.xor == Some(true) {
+                        run.flush(output)?;
+                    }
+                    run.xor = Some(false);
+                    payload.read_to_end(&mut run.data)?;
+                    run.segments.push(Segment::Diff(count));
+                }
+                pos += count;
+            }
+            Op::Xor(mut payload) => {
+                let count = payload.len();
+                if run.xor == Some(false) {
+                    run.flush(output)?;
+                }
+                run.symmetric = true;
+                if let Some(codec) = payload.codec() {
+                    run.flush(output)?;
+                    let mut data = Vec::with_capacity(count as usize);
+                    payload.read_to_end(&mut data)?;
+                    output.write_all(&[OP_XOR])?;
+                    write_op_cdiff(output, codec, &data)?;
+                } else if count > MAX_RUN_DATA {
+                    run.flush(output)?;
+                    stream_diff(output, true, &mut payload, count)?;
+                } else {
+                    run.xor = Some(true);
+                    payload.read_to_end(&mut run.data)?;
+                    run.segments.push(Segment::Diff(count));
+                }
+                pos += count;
+            }
+            Op::DictDiff(compressed) => {
+                run.flush(output)?;
+                let (offset, codec) = (compressed.offset(), compressed.codec());
+                let mut dict = vec![0u8; compressed.dict_len() as usize];
+                file_a.seek(SeekFrom::Start(offset))?;
+                file_a.read_exact(&mut dict)?;
+                let mut payload = compressed.decompress(&dict)?;
+                let count = payload.len();
+                let mut data = Vec::with_capacity(count as usize);
+                payload.read_to_end(&mut data)?;
+                write_op_cdiff_a(output, codec, offset, &dict, &data)?;
+                pos += count;
+            }
+            Op::Copy { offset, count } => {
+                run.flush(output)?;
+                write_op_copy(output, offset, count)?;
+                pos += count;
+            }
+            Op::CopyB { offset, count } => {
+                run.flush(output)?;
+                write_op_copy_b(output, offset, count)?;
+                pos += count;
+            }
+            Op::Add { offset, mut data } => {
+                run.flush(output)?;
+                let count = data.len();
+                output.write_all(&[OP_ADD])?;
+                write_varint(output, offset)?;
+                match data.codec() {
+                    Some(codec) => {
+                        let mut diffs = Vec::with_capacity(count as usize);
+                        data.read_to_end(&mut diffs)?;
+                        write_op_cdiff(output, codec, &diffs)?;
+                    }
+                    None => stream_diff(output, false, &mut data, count)?,
+                }
+                pos += count;
+            }
+            Op::LenB(len) => {
+                run.flush(output)?;
+                write_op_len_b(output, len)?;
+            }
+            Op::HashB(hash) => {
+                run.flush(output)?;
+                write_op_hash_b(output, hash)?;
+            }
+            Op::End => {
+                run.flush(output)?;
+                write_op_end(output)?;
+                break;
+            }
+        }
+        run.flush_if_full(output)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * The size of the records which the plan writes for the segments, or None
+     * if it can't write them.
+     */
+    fn cost(segments: &[Segment], plan: &[Plan], xor: bool) -> Option<u64> {
+        let header = if xor { 2 } else { 1 };
+        let mut total = 0;
+        let mut segments = segments;
+        for step in plan {
+            match *step {
+                Plan::Keep => {
+                    total += segments.first()?.cost()?;
+                    segments = &segments[1..];
+                }
+                Plan::Diff(n) => {
+                    let joined = segments.get(..n)?;
+                    if n == 0 || !joined.iter().all(|segment| segment.absorbable(xor)) {
+                        return None;
+                    }
+                    let len: u64 = joined.iter().map(|segment| segment.len()).sum();
+                    total += header + varint_len(len) + len;
+                    segments = &segments[n..];
+                }
+            }
+        }
+        if segments.is_empty() { Some(total) } else { None }
+    }
+
+    /*
+     * The cost of the cheapest plan, found by trying every one.
+     */
+    fn brute_force(segments: &[Segment], xor: bool) -> u64 {
+        let mut cheapest = u64::MAX;
+        if segments.is_empty() {
+            return 0;
+        }
+        if let Some(cost) = segments[0].cost() {
+            cheapest = cost + brute_force(&segments[1..], xor);
+        }
+        for n in 1..=segments.len() {
+            if !segments[n - 1].absorbable(xor) {
+                break;
+            }
+            let len: u64 = segments[..n].iter().map(|segment| segment.len()).sum();
+            let header = if xor { 2 } else { 1 };
+            cheapest = cheapest.min(header + varint_len(len) + len + brute_force(&segments[n..], xor));
+        }
+        cheapest
+    }
+
+    #[test]
+    fn plan_is_cheapest() {
+        let mut seed = 0x2545F4914F6CDD1Du64;
+        let mut random = |n: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % n
+        };
+        for _ in 0..20000 {
+            let count = random(9) as usize;
+            let segments: Vec<Segment> = (0..count)
+                .map(|_| {
+                    // short enough to be absorbed, or around where the varints get longer
+                    let len = match random(4) {
+                        0 => 1 + random(70),
+                        1 => 100 + random(60),
+                        2 => 16300 + random(200),
+                        _ => 1 + random(8),
+                    };
+                    match random(4) {
+                        0 => Segment::Skip(len),
+                        1 => Segment::Diff(len),
+                        2 => Segment::Hole(len),
+                        _ => Segment::Fill(random(256) as u8, len),
+                    }
+                })
+                .collect();
+            for xor in [false, true] {
+                let plan = plan(&segments, xor);
+                assert_eq!(cost(&segments, &plan, xor), Some(brute_force(&segments, xor)), "{:?} {}", segments, xor);
+            }
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use std::fmt;
+use std::io::{self, ErrorKind, Read};
+use crate::common::*;
+use crate::compress::Codec;
+use crate::error::{Result, VsError};
+use crate::filter::{Filter, Region};
+
+/*
+ * A single record of a delta, as described in README.md.
+ */
+#[derive(Debug)]
+pub enum Op<'a, R> {
+    Ver([u8; 3]),
+    LenA(u64),
+    HashA([u8; 32]),
+    Filter { filter: Filter, regions_a: Vec<Region>, regions_b: Vec<Region> },
+    Skip(u64),
+    Diff(Payload<'a, R>),
+    DictDiff(DictPayload<'a, R>),
+    Hole(u64),
+    Fill { byte: u8, count: u64 },
+    Copy { offset: u64, count: u64 },
+    CopyB { offset: u64, count: u64 },
+    Add { offset: u64, data: Payload<'a, R> },
+    Xor(Payload<'a, R>),
+    LenB(u64),
+    HashB([u8; 32]),
+    End,
+}
+
+/*
+ * Parses a delta into a sequence of Ops.
+ *
+ * The magic is checked when the reader is created.  OP_DIFF data is not
+ * buffered: it is read from the delta through the Op's Payload.  Any data
+ * which is not read before the next call to next_op() is skipped.  An OP_CDIFF
+ * is decompressed into a buffer, and is then read in the same way, as an
+ * Op::Diff.  An OP_CDIFF_A is returned as an Op::DictDiff, as it can only be
+ * decompressed once its dictionary has been read from file_a.
+ */
+pub struct DeltaReader<R> {
+    delta: R,
+    remaining: u64,        // unread bytes of the current OP_DIFF's data
+    varint: bool,          // counts and lengths are varints, see VARINT_VERSION
+    codec: Option<Codec>,  // set if the current data was compressed
+    buffer: Vec<u8>,       // the decompressed data, if it was
+    compressed: Vec<u8>,   // the data of an OP_CDIFF_A, until it is decompressed
+}
+
+/*
+ * The data of an OP_DIFF, read directly from the delta, or of an OP_CDIFF,
+ * read from its decompressed buffer.  The differences of an OP_ADD, and the
+ * data of an OP_XOR, are read in the same way.
+ */
+pub struct Payload<'a, R> {
+    reader: &'a mut DeltaReader<R>,
+}
+
+/*
+ * Like read_exact(), but running out of delta is reported as VsError::Truncated.
+ */
+fn read_delta<R: Read>(delta: &mut R, buf: &mut [u8]) -> Result<()> {
+    delta.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => VsError::Truncated,
+        _ => VsError::Io(e),
+    })
+}
+
+/*
+ * The compressed data of an OP_CDIFF_A, which becomes a Payload once it has
+ * been decompressed with "dict_len" bytes of file_a, at "offset".
+ */
+pub struct DictPayload<'a, R> {
+    reader: &'a mut DeltaReader<R>,
+    codec: Codec,
+    offset: u64,
+    dict_len: u64,
+    count: u64,
+}
+
+impl<R: Read> DeltaReader<R> {
+    pub fn new(mut delta: R) -> Result<DeltaReader<R>> {
+        let mut buf = [0u8; 7];
+        read_delta(&mut delta, &mut buf).map_err(|e| match e {
+            VsError::Truncated => VsError::BadMagic,
+            e => e,
+        })?;
+        if &buf != b"vsdelta" {
+            return Err(VsError::BadMagic);
+        }
+        Ok(DeltaReader { delta, remaining: 0, varint: false, codec: None, buffer: Vec::new(), compressed: Vec::new() })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.delta
+    }
+
+    /*
+     * Reads a count or length, in whichever encoding OP_VER specified.
+     */
+    fn read_u64(&mut self) -> Result<u64> {
+        if self.varint {
+            return read_varint(&mut self.delta).map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => VsError::Truncated,
+                _ => VsError::Io(e),
+            });
+        }
+        let mut buf = [0u8; 8];
+        read_delta(&mut self.delta, &mut buf)?;
+        Ok(u8aletou64(buf))
+    }
+
+    fn read_hash(&mut self) -> Result<[u8; 32]> {
+        let mut buf = [0u8; 32];
+        read_delta(&mut self.delta, &mut buf)?;
+        Ok(buf)
+    }
+
+    /*
+     * Reads and decompresses the data of an OP_CDIFF into the buffer.
+     */
+    fn read_cdiff(&mut self) -> Result<()> {
+        let mut codec = [0u8; 1];
+        read_delta(&mut self.delta, &mut codec)?;
+        let codec = Codec::from_u8(codec[0]).ok_or(VsError::BadRecord(OP_CDIFF))?;
+        let count = self.read_u64()?;
+        let clen = self.read_u64()?;
+        if count > MAX_CDIFF_COUNT || clen > count {
+            return Err(VsError::BadRecord(OP_CDIFF));
+        }
+
+        let mut compressed = vec![0u8; clen as usize];
+        read_delta(&mut self.delta, &mut compressed)?;
+        self.buffer = codec.decompress(&compressed, count as usize).map_err(|_| VsError::BadRecord(OP_CDIFF))?;
+        self.codec = Some(codec);
+        self.remaining = count;
+        Ok(())
+    }
+
+    /*
+     * Reads the count, offsets and lengths of the regions of an OP_FILTER.
+     */
+    fn read_regions(&mut self) -> Result<Vec<Region>> {
+        let count = self.read_u64()?;
+        if count > MAX_FILTER_REGIONS {
+            return Err(VsError::BadRecord(OP_FILTER));
+        }
+        let mut regions = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let offset = self.read_u64()?;
+            let len = self.read_u64()?;
+            regions.push(Region { offset, len });
+        }
+        Ok(regions)
+    }
+
+    /*
+     * Reads the count of an OP_DIFF, whose data is then read by the Payload, or
+     * decompresses an OP_CDIFF.
+     */
+    fn read_diff(&mut self, opcode: u8) -> Result<()> {
+        if opcode == OP_CDIFF {
+            return self.read_cdiff();
+        }
+        self.remaining = self.read_u64()?;
+        Ok(())
+    }
+
+    /*
+     * Reads the header and compressed data of an OP_CDIFF_A.
+     */
+    fn read_cdiff_a(&mut self) -> Result<DictPayload<'_, R>> {
+        let mut codec = [0u8; 1];
+        read_delta(&mut self.delta, &mut codec)?;
+        let codec = Codec::from_u8(codec[0]).ok_or(VsError::BadRecord(OP_CDIFF_A))?;
+        let offset = self.read_u64()?;
+        let dict_len = self.read_u64()?;
+        let count = self.read_u64()?;
+        let clen = self.read_u64()?;
+        if dict_len > MAX_DICT_LEN || count > MAX_CDIFF_COUNT || clen > count {
+            return Err(VsError::BadRecord(OP_CDIFF_A));
+        }
+
+        self.compressed.resize(clen as usize, 0);
+        read_delta(&mut self.delta, &mut self.compressed)?;
+        Ok(DictPayload { reader: self, codec, offset, dict_len, count })
+    }
+
+    /*
+     * Reads the next record from the delta.
+     */
+    pub fn next_op(&mut self) -> Result<Op<'_, R>> {
+        if self.codec.is_some() {
+            // unread decompressed data is simply discarded
+            self.codec = None;
+            self.remaining = 0;
+        } else if self.remaining > 0 {
+            // skip any OP_DIFF data which the caller did not read
+            let skipped = io::copy(&mut (&mut self.delta).take(self.remaining), &mut io::sink())?;
+            if skipped != self.remaining {
+                return Err(VsError::Truncated);
+            }
+            self.remaining = 0;
+        }
+
+        let mut opbuf = [0u8; 1];
+        read_delta(&mut self.delta, &mut opbuf)?;
+
+        Ok(match opbuf[0] {
+            OP_VER => {
+                let mut ver = [0u8; 3];
+                read_delta(&mut self.delta, &mut ver)?;
+                self.varint = ver >= VARINT_VERSION;
+                Op::Ver(ver)
+            }
+            OP_LEN_A => Op::LenA(self.read_u64()?),
+            OP_HASH_A => Op::HashA(self.read_hash()?),
+            OP_FILTER => {
+                read_delta(&mut self.delta, &mut opbuf)?;
+                let filter = Filter::from_u8(opbuf[0]).ok_or(VsError::BadRecord(OP_FILTER))?;
+                let regions_a = self.read_regions()?;
+                let regions_b = self.read_regions()?;
+                Op::Filter { filter, regions_a, regions_b }
+            }
+            OP_SKIP => Op::Skip(self.read_u64()?),
+            OP_DIFF | OP_CDIFF => {
+                self.read_diff(opbuf[0])?;
+                Op::Diff(Payload { reader: self })
+            }
+            OP_CDIFF_A => Op::DictDiff(self.read_cdiff_a()?),
+            OP_HOLE => Op::Hole(self.read_u64()?),
+            OP_FILL => {
+                read_delta(&mut self.delta, &mut opbuf)?;
+                Op::Fill { byte: opbuf[0], count: self.read_u64()? }
+            }
+            OP_COPY => {
+                let offset = self.read_u64()?;
+                let count = self.read_u64()?;
+                Op::Copy { offset, count }
+            }
+            OP_COPY_B => {
+                let offset = self.read_u64()?;
+                let count = self.read_u64()?;
+                Op::CopyB { offset, count }
+            }
+            OP_ADD => {
+                let offset = self.read_u64()?;
+                read_delta(&mut self.delta, &mut opbuf)?;
+                if opbuf[0] != OP_DIFF && opbuf[0] != OP_CDIFF {
+                    return Err(VsError::BadRecord(OP_ADD));
+                }
+                self.read_diff(opbuf[0])?;
+                Op::Add { offset, data: Payload { reader: self } }
+            }
+            OP_XOR => {
+                read_delta(&mut self.delta, &mut opbuf)?;
+                if opbuf[0] != OP_DIFF && opbuf[0] != OP_CDIFF {
+                    return Err(VsError::BadRecord(OP_XOR));
+                }
+                self.read_diff(opbuf[0])?;
+                Op::Xor(Payload { reader: self })
+            }
+            OP_LEN_B => Op::LenB(self.read_u64()?),
+            OP_HASH_B => Op::HashB(self.read_hash()?),
+            OP_END => Op::End,
+            opcode => return Err(VsError::UnknownOpcode(opcode)),
+        })
+    }
+}
+
+impl<'a, R> Payload<'a, R> {
+    /*
+     * The number of bytes of data which have not yet been read.
+     */
+    pub fn len(&self) -> u64 {
+        self.reader.remaining
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reader.remaining == 0
+    }
+
+    /*
+     * The codec the data was compressed with, if it came from an OP_CDIFF.
+     */
+    pub fn codec(&self) -> Option<Codec> {
+        self.reader.codec
+    }
+}
+
+impl<'a, R> DictPayload<'a, R> {
+    /*
+     * The offset of the dictionary in file_a.
+     */
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn dict_len(&self) -> u64 {
+        self.dict_len
+    }
+
+    /*
+     * The number of bytes of data, once decompressed.
+     */
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /*
+     * Decompresses the data, with the dictionary read from file_a.
+     */
+    pub fn decompress(self, dict: &[u8]) -> Result<Payload<'a, R>> {
+        if dict.len() as u64 != self.dict_len {
+            return Err(VsError::BadRecord(OP_CDIFF_A));
+        }
+        let reader = self.reader;
+        reader.buffer = self.codec.decompress_with_dict(&reader.compressed, self.count as usize, dict)
+            .map_err(|_| VsError::BadRecord(OP_CDIFF_A))?;
+        reader.codec = Some(self.codec);
+        reader.remaining = self.count;
+        Ok(Payload { reader })
+    }
+}
+
+impl<'a, R: Read> Read for Payload<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = std::cmp::min(buf.len() as u64, self.reader.remaining) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        if self.reader.codec.is_some() {
+            let start = self.reader.buffer.len() - self.reader.remaining as usize;
+            buf[..max].copy_from_slice(&self.reader.buffer[start..start + max]);
+            self.reader.remaining -= max as u64;
+            return Ok(max);
+        }
+        let num = self.reader.delta.read(&mut buf[..max])?;
+        if num == 0 {
+            return Err(VsError::Truncated.into());
+        }
+        self.reader.remaining -= num as u64;
+        Ok(num)
+    }
+}
+
+impl<'a, R> fmt::Debug for DictPayload<'a, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DictPayload")
+            .field("codec", &self.codec)
+            .field("offset", &self.offset)
+            .field("dict_len", &self.dict_len)
+            .field("len", &self.count)
+            .finish()
+    }
+}
+
+impl<'a, R> fmt::Debug for Payload<'a, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Payload").field("len", &self.len()).field("codec", &self.codec()).finish()
+    }
+}
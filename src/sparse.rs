@@ -0,0 +1,215 @@
+use std::fs::File;
+use std::ops::Range;
+
+/*
+ * Finds the extents of a regular file which are data, rather than holes, in
+ * order, with lseek()'s SEEK_DATA and SEEK_HOLE.  The holes between them read
+ * as zeros.  Gives None if the extents can't be found, in which case all of
+ * the file should be treated as data.  Filesystems which don't keep track of
+ * holes give one extent for the whole file.
+ *
+ * The file is left at the position it was at.
+ */
+pub fn data_extents(file: &File) -> Option<Vec<Range<u64>>> {
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    data_extents_between(file, 0, metadata.len())
+}
+
+/*
+ * Like data_extents(), but only finds the extents from "from" to "to", which
+ * are cut to fit.
+ */
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn data_extents_between(file: &File, from: u64, to: u64) -> Option<Vec<Range<u64>>> {
+    use std::cmp::min;
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    // lseek() moves the file, so it is put back afterwards
+    let resume = unsafe { libc::lseek(fd, 0, libc::SEEK_CUR) };
+    if resume < 0 {
+        return None;
+    }
+
+    let mut extents = Vec::new();
+    let mut pos = from;
+    let found = loop {
+        if pos >= to {
+            break true;
+        }
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            // ENXIO means there is no more data before the end of the file
+            break Error::last_os_error().raw_os_error() == Some(libc::ENXIO);
+        }
+        if data as u64 >= to {
+            break true;
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole <= data {
+            break false;
+        }
+        extents.push(data as u64..min(hole as u64, to));
+        pos = hole as u64;
+    };
+    unsafe { libc::lseek(fd, resume, libc::SEEK_SET) };
+    if found { Some(extents) } else { None }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn data_extents_between(_file: &File, _from: u64, _to: u64) -> Option<Vec<Range<u64>>> {
+    None
+}
+
+/*
+ * Returns whether "from" to "to" is all hole, given the extents which are data.
+ */
+pub fn is_hole(data: &[Range<u64>], from: u64, to: u64) -> bool {
+    let next = data.partition_point(|extent| extent.end <= from);
+    data.get(next).is_none_or(|extent| extent.start >= to)
+}
+
+/*
+ * Finds the ranges of file_b which are the same physical blocks as file_a at
+ * the same positions, such as in a reflinked copy of file_a, with the FIEMAP
+ * ioctl, in order.  Those ranges are the same in both files without being
+ * compared.  Gives None if the extents can't be found.
+ */
+#[cfg(target_os = "linux")]
+pub fn shared_extents(file_a: &File, file_b: &File) -> Option<Vec<Range<u64>>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (meta_a, meta_b) = (file_a.metadata().ok()?, file_b.metadata().ok()?);
+    if !meta_a.is_file() || !meta_b.is_file() || meta_a.dev() != meta_b.dev() {
+        return None;
+    }
+    let len = meta_a.len().min(meta_b.len());
+    let (extents_a, extents_b) = (fiemap::extents(file_a, len)?, fiemap::extents(file_b, len)?);
+
+    let mut shared: Vec<Range<u64>> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < extents_a.len() && j < extents_b.len() {
+        let (a, b) = (&extents_a[i], &extents_b[j]);
+        let from = a.logical.max(b.logical);
+        let to = (a.logical + a.length).min(b.logical + b.length).min(len);
+        // the same logical offset is at the same physical offset in both
+        if from < to && a.physical.wrapping_sub(a.logical) == b.physical.wrapping_sub(b.logical) {
+            match shared.last_mut() {
+                Some(last) if last.end == from => last.end = to,
+                _ => shared.push(from..to),
+            }
+        }
+        if a.logical + a.length <= b.logical + b.length {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Some(shared)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn shared_extents(_file_a: &File, _file_b: &File) -> Option<Vec<Range<u64>>> {
+    None
+}
+
+/*
+ * Returns whether "from" to "to" is all within the extents.
+ */
+pub fn is_within(extents: &[Range<u64>], from: u64, to: u64) -> bool {
+    let next = extents.partition_point(|extent| extent.end <= from);
+    extents.get(next).is_some_and(|extent| extent.start <= from && extent.end >= to)
+}
+
+#[cfg(target_os = "linux")]
+mod fiemap {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    // from linux/fiemap.h
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: u32 = 0xC020660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+    // the extent's physical offset doesn't say where its data is, or its data
+    // isn't there yet: UNKNOWN, DELALLOC, ENCODED, DATA_ENCRYPTED,
+    // NOT_ALIGNED, DATA_INLINE, DATA_TAIL and UNWRITTEN
+    const FIEMAP_EXTENT_ELSEWHERE: u32 = 0x2 | 0x4 | 0x8 | 0x80 | 0x100 | 0x200 | 0x400 | 0x800;
+
+    // how many extents are asked for at once
+    const EXTENT_COUNT: usize = 512;
+
+    /*
+     * A shared extent of a file, whose data is at "physical" on the device.
+     */
+    pub struct Extent {
+        pub logical: u64,
+        pub physical: u64,
+        pub length: u64,
+    }
+
+    /*
+     * Returns the shared extents of the first "len" bytes of file, in order.
+     * Data which hasn't been written yet is written first.
+     */
+    pub fn extents(file: &File, len: u64) -> Option<Vec<Extent>> {
+        #[repr(C)]
+        struct Request {
+            header: Fiemap,
+            extents: [FiemapExtent; EXTENT_COUNT],
+        }
+
+        let mut extents = Vec::new();
+        let mut start = 0;
+        while start < len {
+            let mut request = Request {
+                header: Fiemap { start, length: len - start, flags: FIEMAP_FLAG_SYNC, mapped_extents: 0, extent_count: EXTENT_COUNT as u32, reserved: 0 },
+                extents: [FiemapExtent::default(); EXTENT_COUNT],
+            };
+            if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut request) } != 0 {
+                return None;
+            }
+            let mapped = &request.extents[..(request.header.mapped_extents as usize).min(EXTENT_COUNT)];
+            let last = match mapped.last() {
+                Some(last) => last,
+                None => break,
+            };
+            for extent in mapped {
+                if extent.flags & FIEMAP_EXTENT_SHARED != 0 && extent.flags & FIEMAP_EXTENT_ELSEWHERE == 0 {
+                    extents.push(Extent { logical: extent.logical, physical: extent.physical, length: extent.length });
+                }
+            }
+            if last.flags & FIEMAP_EXTENT_LAST != 0 || last.logical + last.length <= start {
+                break;
+            }
+            start = last.logical + last.length;
+        }
+        Some(extents)
+    }
+}
@@ -0,0 +1,232 @@
+#[cfg(target_os = "linux")]
+use std::cmp::min;
+use std::fs::File;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::io::Error;
+use std::io::{Cursor, Result, SeekFrom};
+use std::io::prelude::*;
+use std::ops::Range;
+
+/*
+ * Somewhere the result of applying a delta can be written.
+ *
+ * As well as being readable (its hash is checked after it is written),
+ * writable and seekable, it must be possible to truncate or extend it.
+ */
+pub trait Storage: Read + Write + Seek {
+    fn set_len(&mut self, len: u64) -> Result<()>;
+
+    /*
+     * Makes sure that everything written so far is visible to subsequent reads
+     * and length queries.
+     */
+    fn sync(&mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /*
+     * Zeros "len" bytes at "offset", which must be within the storage, by
+     * deallocating them, without moving its position.  Returns false, having
+     * done nothing, if holes can't be punched, so the zeros must be written.
+     */
+    fn punch_hole(&mut self, _offset: u64, _len: u64) -> Result<bool> {
+        Ok(false)
+    }
+
+    /*
+     * Copies "len" bytes at "offset" in src to the storage's position, without
+     * reading them, such as by sharing src's blocks, and moves past them.
+     * Returns how many bytes were copied, which may be none, so the rest must
+     * be read and written.
+     */
+    fn copy_range(&mut self, _src: &File, _offset: u64, _len: u64) -> Result<u64> {
+        Ok(0)
+    }
+
+    /*
+     * The extents of the storage which are data, rather than holes, as from
+     * data_extents(), so that the holes can be hashed without being read.
+     * None if they aren't known.
+     */
+    fn data_extents(&mut self) -> Option<Vec<Range<u64>>> {
+        None
+    }
+}
+
+impl Storage for File {
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.sync_all()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn punch_hole(&mut self, offset: u64, len: u64) -> Result<bool> {
+        use std::os::unix::io::AsRawFd;
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        if unsafe { libc::fallocate(self.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) } == 0 {
+            return Ok(true);
+        }
+        let error = Error::last_os_error();
+        match error.raw_os_error() {
+            // the filesystem or device doesn't support it, or (for a device)
+            // the hole isn't aligned to its blocks
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) | Some(libc::ENODEV) | Some(libc::EINVAL) => Ok(false),
+            _ => Err(error),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn copy_range(&mut self, src: &File, offset: u64, len: u64) -> Result<u64> {
+        use std::os::unix::fs::MetadataExt;
+        let pos = self.stream_position()?;
+        let block = self.metadata()?.blksize();
+        let mut done = 0;
+        // whole blocks are cloned, so that they are shared with src, which
+        // needs them to be at the same offset within a block in both files
+        if block > 0 && offset % block == pos % block {
+            let head = min(len, (block - offset % block) % block);
+            let blocks = (len - head) / block * block;
+            if blocks > 0 {
+                done = copy_file_range(src, offset, self, pos, head)?;
+                if done == head && clone_range(src, offset + head, self, pos + head, blocks)? {
+                    done += blocks;
+                }
+            }
+        }
+        // and anything else is copied by the kernel
+        done += copy_file_range(src, offset + done, self, pos + done, len - done)?;
+        self.seek(SeekFrom::Start(pos + done))?;
+        Ok(done)
+    }
+
+    fn data_extents(&mut self) -> Option<Vec<Range<u64>>> {
+        crate::sparse::data_extents(self)
+    }
+}
+
+/*
+ * Shares "len" bytes at "src_offset" in src with dst at "dst_offset", as
+ * FICLONERANGE does on btrfs and XFS.  Returns false if they can't be shared.
+ */
+#[cfg(target_os = "linux")]
+fn clone_range(src: &File, src_offset: u64, dst: &File, dst_offset: u64, len: u64) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let range = libc::file_clone_range {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset,
+        src_length: len,
+        dest_offset: dst_offset,
+    };
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONERANGE, &range) } == 0 {
+        return Ok(true);
+    }
+    let error = Error::last_os_error();
+    match error.raw_os_error() {
+        // the filesystem doesn't support it, the files are on different
+        // filesystems, or the blocks aren't aligned
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EXDEV) | Some(libc::EINVAL) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/*
+ * Copies up to "len" bytes at "src_offset" in src to "dst_offset" in dst, with
+ * copy_file_range(), returning how many were copied.  Neither file is moved.
+ */
+#[cfg(target_os = "linux")]
+fn copy_file_range(src: &File, src_offset: u64, dst: &File, dst_offset: u64, len: u64) -> Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    let mut done = 0;
+    while done < len {
+        let mut off_in = (src_offset + done) as _;
+        let mut off_out = (dst_offset + done) as _;
+        let chunk = min(len - done, 1 << 30) as usize;
+        let num = unsafe { libc::copy_file_range(src.as_raw_fd(), &mut off_in, dst.as_raw_fd(), &mut off_out, chunk, 0) };
+        if num < 0 {
+            let error = Error::last_os_error();
+            match error.raw_os_error() {
+                // the kernel or the filesystem can't, so the rest is read and written
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) | Some(libc::EXDEV) | Some(libc::EINVAL) => break,
+                _ => return Err(error),
+            }
+        }
+        if num == 0 {
+            break;
+        }
+        done += num as u64;
+    }
+    Ok(done)
+}
+
+impl Storage for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+impl Storage for Cursor<&mut Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for &mut S {
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        (**self).set_len(len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> Result<bool> {
+        (**self).punch_hole(offset, len)
+    }
+
+    fn copy_range(&mut self, src: &File, offset: u64, len: u64) -> Result<u64> {
+        (**self).copy_range(src, offset, len)
+    }
+
+    fn data_extents(&mut self) -> Option<Vec<Range<u64>>> {
+        (**self).data_extents()
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        (**self).set_len(len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> Result<bool> {
+        (**self).punch_hole(offset, len)
+    }
+
+    fn copy_range(&mut self, src: &File, offset: u64, len: u64) -> Result<u64> {
+        (**self).copy_range(src, offset, len)
+    }
+
+    fn data_extents(&mut self) -> Option<Vec<Range<u64>>> {
+        (**self).data_extents()
+    }
+}
+
+/*
+ * Returns the length of a stream, without moving its position.
+ */
+pub fn stream_len<S: Seek + ?Sized>(stream: &mut S) -> Result<u64> {
+    let pos = stream.stream_position()?;
+    let len = stream.seek(SeekFrom::End(0))?;
+    if pos != len {
+        stream.seek(SeekFrom::Start(pos))?;
+    }
+    Ok(len)
+}
@@ -0,0 +1,1343 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::io::{Cursor, Error, ErrorKind, SeekFrom, Result};
+use std::cmp::{max, min};
+use std::ops::Range;
+use std::thread;
+use std::io::prelude::*;
+use crate::common::*;
+use crate::compress::Codec;
+use crate::filter::{detect, Filter, Region};
+use crate::approx::find_adds;
+use crate::moves::find_moves;
+use crate::optimise::{plan, Plan, Segment};
+use crate::sparse::{is_hole, is_within};
+use crate::storage::stream_len;
+use crate::built_info;
+
+#[derive(Debug)]
+enum State {
+    Init,
+    Matching(u64),
+    Different(u64, u64, u8, u64), // count, how many of those bytes are a trailing run of one value in file_b, that value, and how many are a trailing match with file_a
+    Hole(u64),
+    Fill(u64, u8),
+}
+
+// without compression, every hole, fill and match is split out of different
+// data, and plan() chooses which are worth their own records.  Compressed
+// data is only split by a match this long, as that also costs the codec's
+// header, or by a run this long, as runs compress well anyway.
+const MIN_COMPRESSED_SKIP_LEN: u64 = 2 * (1 + 3);
+const MIN_COMPRESSED_RUN_LEN: u64 = 64;
+
+// the most segments which are held for plan() at once
+const MAX_PENDING: usize = 64 * 1024;
+
+// the files are read, and compared, in spans of about this many bytes, which
+// are compared in parallel
+const SPAN_LEN: u64 = 4 * 1024 * 1024;
+// the largest block_size, as each block is held in memory, and is no larger
+// than a span
+const MAX_BLOCK_LEN: u64 = SPAN_LEN;
+
+// within an OP_ADD, zeros which differ from file_a cost a byte each, before
+// compression, so a run this long is split out to become an OP_HOLE
+const MIN_ADD_HOLE_LEN: u64 = 64;
+
+// the most data which is compressed into a single OP_CDIFF, so that the
+// applier only has to hold this much at once
+const CDIFF_RECORDLEN: u64 = 1024 * 1024;
+
+// how much of file_a either side of an OP_CDIFF_A is used as its dictionary,
+// which is about as much as the data itself, within these limits.  The short
+// records between small changes find little to match in a few KiB, the more
+// so in-place, where the dictionary only reaches forwards; 64 KiB is also as
+// much as lz4 uses.
+const MIN_DICT_MARGIN: u64 = 64 * 1024;
+const MAX_DICT_MARGIN: u64 = 1024 * 1024;
+// smaller data is compressed without a dictionary, as the offset and length
+// of one cost more than it saves, and it isn't worth reading
+const MIN_DICT_COUNT: u64 = 64;
+
+/*
+ * Controls which records are used to describe file_b.
+ */
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    // look for data which has moved within the file, or is repeated, and emit
+    // OP_COPYs and OP_COPY_Bs for it.  Both files are read into memory.
+    pub moves: bool,
+    // look for data which is approximately the same as data elsewhere in
+    // file_a, and emit OP_ADDs of the differences, in the manner of bsdiff.
+    // Both files are read into memory.  This replaces "moves", and is only
+    // worthwhile with compression.
+    pub approx: bool,
+    // normalise the targets of calls and branches in the code of x86-64 and
+    // ARM64 ELF files, so that they are the same when the code has moved.
+    // Both files are read into memory.
+    pub filter: bool,
+    // only emit records which can be applied in-place
+    pub in_place: bool,
+    // compress the data of each OP_DIFF, if that makes it smaller
+    pub compression: Option<Codec>,
+    // when compressing, use the nearby data of file_a as a dictionary
+    pub dictionary: bool,
+    // describe file_b only with OP_SKIPs and OP_XORs, so that the delta can
+    // also be applied in reverse, turning file_b into file_a.  The files must
+    // be the same length, and this can't be combined with moves, approx,
+    // filter or dictionary.
+    pub xor: bool,
+    // file_b is compared with file_a in blocks of this many bytes, and blocks
+    // which differ are compared again in chunks of "chunk_size" bytes, whose
+    // differences are then trimmed to the bytes which differ.  Larger blocks
+    // are faster, smaller chunks find more of the data which is the same.
+    // Blocks may be at most 4 MiB.
+    pub block_size: u64,
+    pub chunk_size: u64,
+    // compare this many spans of the files at once, each on its own thread,
+    // while the next are read, and hash the files on this many threads.  The
+    // delta is the same for any number.
+    pub threads: usize,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            moves: false,
+            approx: false,
+            filter: false,
+            in_place: false,
+            compression: None,
+            dictionary: false,
+            xor: false,
+            block_size: BIGCHUNKLEN,
+            chunk_size: CHUNKLEN,
+            threads: 1,
+        }
+    }
+}
+
+// the extents of a file which are data, rather than holes, in order
+type Extents<'a> = &'a [Range<u64>];
+
+/*
+ * Creates a delta which transforms file_a (the base) into file_b (the target).
+ */
+pub struct DeltaWriter<'a, A: ?Sized, B: ?Sized> {
+    file_a: &'a mut A,
+    file_b: &'a mut B,
+    options: WriteOptions,
+    // the contents of file_a and file_b, if they are already in memory
+    memory: Option<(&'a [u8], &'a [u8])>,
+    // the extents of file_a and file_b which are data, if they are known
+    extents: Option<(Extents<'a>, Extents<'a>)>,
+    // the extents of file_b which are the same as file_a, if they are known
+    shared: Option<Extents<'a>>,
+}
+
+pub(crate) fn write_magic<W: Write>(delta: &mut W) -> Result<()> {
+    delta.write_all("vsdelta".as_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn write_op_ver<W: Write>(delta: &mut W, version: [u8; 3]) -> Result<()> {
+    delta.write_all(&[OP_VER])?;
+    delta.write_all(&version)?;
+    Ok(())
+}
+
+/*
+ * The version of the format which is written, which is that of the crate.
+ */
+pub(crate) fn version() -> [u8; 3] {
+    [
+        built_info::PKG_VERSION_MAJOR.parse::<u8>().unwrap(),
+        built_info::PKG_VERSION_MINOR.parse::<u8>().unwrap(),
+        built_info::PKG_VERSION_PATCH.parse::<u8>().unwrap(),
+    ]
+}
+
+pub(crate) fn write_op_len_a<W: Write>(delta: &mut W, alen: u64) -> Result<()> {
+    delta.write_all(&[OP_LEN_A])?;
+    write_varint(delta, alen)?;
+    Ok(())
+}
+
+pub(crate) fn write_op_hash_a<W: Write>(delta: &mut W, hash_a: [u8; 32]) -> Result<()> {
+    delta.write_all(&[OP_HASH_A])?;
+    delta.write_all(&hash_a)?;
+    Ok(())
+}
+
+pub(crate) fn write_op_hash_b<W: Write>(delta: &mut W, hash_b: [u8; 32]) -> Result<()> {
+    delta.write_all(&[OP_HASH_B])?;
+    delta.write_all(&hash_b)?;
+    Ok(())
+}
+
+pub(crate) fn write_op_filter<W: Write>(delta: &mut W, filter: Filter, regions_a: &[Region], regions_b: &[Region]) -> Result<()> {
+    delta.write_all(&[OP_FILTER, filter.to_u8()])?;
+    for regions in [regions_a, regions_b] {
+        write_varint(delta, regions.len() as u64)?;
+        for region in regions {
+            write_varint(delta, region.offset)?;
+            write_varint(delta, region.len)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn write_op_len_b<W: Write>(delta: &mut W, blen: u64) -> Result<()> {
+    delta.write_all(&[OP_LEN_B])?;
+    write_varint(delta, blen)?;
+    Ok(())
+}
+
+pub(crate) fn write_op_end<W: Write>(delta: &mut W) -> Result<()> {
+    delta.write_all(&[OP_END])?;
+    Ok(())
+}
+
+pub(crate) fn write_op_skip<W: Write>(delta: &mut W, count: u64) -> Result<()> {
+    delta.write_all(&[OP_SKIP])?;
+    write_varint(delta, count)
+}
+
+pub(crate) fn write_op_hole<W: Write>(delta: &mut W, count: u64) -> Result<()> {
+    delta.write_all(&[OP_HOLE])?;
+    write_varint(delta, count)
+}
+
+pub(crate) fn write_op_fill<W: Write>(delta: &mut W, byte: u8, count: u64) -> Result<()> {
+    delta.write_all(&[OP_FILL, byte])?;
+    write_varint(delta, count)
+}
+
+pub(crate) fn write_op_copy<W: Write>(delta: &mut W, offset: u64, count: u64) -> Result<()> {
+    delta.write_all(&[OP_COPY])?;
+    write_varint(delta, offset)?;
+    write_varint(delta, count)
+}
+
+pub(crate) fn write_op_copy_b<W: Write>(delta: &mut W, offset: u64, count: u64) -> Result<()> {
+    delta.write_all(&[OP_COPY_B])?;
+    write_varint(delta, offset)?;
+    write_varint(delta, count)
+}
+
+pub(crate) fn write_op_diff<W: Write>(delta: &mut W, data: &[u8]) -> Result<()> {
+    delta.write_all(&[OP_DIFF])?;
+    write_varint(delta, data.len() as u64)?;
+    delta.write_all(data)
+}
+
+/*
+ * Writes data as an OP_CDIFF, or as a plain OP_DIFF if compressing it
+ * doesn't save anything.
+ */
+pub(crate) fn write_op_cdiff<W: Write>(delta: &mut W, codec: Codec, data: &[u8]) -> Result<()> {
+    let count = data.len() as u64;
+    let compressed = codec.compress(data)?;
+    let clen = compressed.len() as u64;
+
+    // an OP_CDIFF also has a codec and a compressed length
+    if 1 + varint_len(clen) + clen < count {
+        delta.write_all(&[OP_CDIFF, codec.to_u8()])?;
+        write_varint(delta, count)?;
+        write_varint(delta, clen)?;
+        delta.write_all(&compressed)
+    } else {
+        write_op_diff(delta, data)
+    }
+}
+
+/*
+ * Writes data as an OP_CDIFF_A, whose dictionary is "dict", from "offset" in
+ * file_a, or as a plain OP_DIFF if compressing it doesn't save anything.
+ */
+pub(crate) fn write_op_cdiff_a<W: Write>(delta: &mut W, codec: Codec, offset: u64, dict: &[u8], data: &[u8]) -> Result<()> {
+    let count = data.len() as u64;
+    let compressed = codec.compress_with_dict(data, dict)?;
+    let clen = compressed.len() as u64;
+
+    // an OP_CDIFF_A also has a codec, a dictionary and a compressed length
+    if 1 + varint_len(offset) + varint_len(dict.len() as u64) + varint_len(clen) + clen < count {
+        delta.write_all(&[OP_CDIFF_A, codec.to_u8()])?;
+        write_varint(delta, offset)?;
+        write_varint(delta, dict.len() as u64)?;
+        write_varint(delta, count)?;
+        write_varint(delta, clen)?;
+        delta.write_all(&compressed)
+    } else {
+        write_op_diff(delta, data)
+    }
+}
+
+/* 
+ * Appends the "num" bytes at "pos" in src to dst.
+ */
+fn append_data<W: Write, R: Read + Seek + ?Sized>(dst: &mut W, src: &mut R, num: u64, pos: u64) -> Result<()> {
+
+    let num_chunks = num / BIGCHUNKLEN;
+    let remainder = num - num_chunks * BIGCHUNKLEN;
+
+    src.seek(SeekFrom::Start(pos))?;
+
+    let mut copybuf = vec![0u8; BIGCHUNKSIZE];
+    for _ in 0..num_chunks {
+        src.read_exact(&mut copybuf)?;
+        dst.write_all(&copybuf)?;
+    }
+
+    let mut copybuf = vec![0u8; remainder as usize];
+    src.read_exact(&mut copybuf)?;
+    dst.write_all(&copybuf)?;
+
+    Ok(())
+}
+
+/*
+ * Writes the records which describe the body of file_b.  The pieces of
+ * file_b which the comparison finds are held as segments until plan() has
+ * chosen how to write them.
+ */
+struct RecordWriter<'w, W> {
+    delta: &'w mut W,
+    compression: Option<Codec>,
+    dictionary: bool,
+    in_place: bool,
+    xor: bool,
+    alen: u64,
+    // how file_b is compared with file_a, see WriteOptions
+    block_size: u64,
+    chunk_size: u64,
+    threads: usize,
+    // the segments which haven't been written, and the position of the first
+    pending: Vec<Segment>,
+    pos: u64,
+}
+
+impl<'w, W: Write> RecordWriter<'w, W> {
+    /*
+     * The shortest run of one value, within different data, which is split
+     * out as a hole or fill.
+     */
+    fn min_run_len(&self) -> u64 {
+        if self.compression.is_some() {
+            MIN_COMPRESSED_RUN_LEN
+        } else {
+            1
+        }
+    }
+
+    /*
+     * The shortest match, within different data, which is split out as a skip.
+     */
+    fn min_skip_len(&self) -> u64 {
+        if self.compression.is_some() {
+            MIN_COMPRESSED_SKIP_LEN
+        } else {
+            1
+        }
+    }
+
+    fn push(&mut self, segment: Segment) {
+        self.pending.push(segment);
+    }
+
+    /*
+     * Writes the pending segments, as far as the last which no OP_DIFF can
+     * reach across, or all of them if "all" is set.  Without compression,
+     * plan() chooses which are joined into OP_DIFFs, otherwise only the
+     * different segments are.  The positions of both files are kept.
+     */
+    fn flush<A: Read + Seek + ?Sized, B: Read + Seek + ?Sized>(&mut self, file_a: &mut A, file_b: &mut B, all: bool) -> Result<()> {
+        let num = if all || self.pending.len() >= MAX_PENDING {
+            self.pending.len()
+        } else {
+            self.pending.iter().rposition(|segment| !segment.absorbable(self.xor)).map_or(0, |last| last + 1)
+        };
+        if num == 0 {
+            return Ok(());
+        }
+        let segments: Vec<Segment> = self.pending.drain(..num).collect();
+        let steps = if self.compression.is_none() {
+            plan(&segments, self.xor)
+        } else {
+            segments.iter().map(|segment| match segment {
+                Segment::Diff(_) => Plan::Diff(1),
+                _ => Plan::Keep,
+            }).collect()
+        };
+
+        let resume_a = file_a.stream_position()?;
+        let resume_b = file_b.stream_position()?;
+        let mut segments = segments.into_iter();
+        for step in steps {
+            let len = match step {
+                Plan::Keep => {
+                    let segment = segments.next().unwrap();
+                    match segment {
+                        Segment::Skip(count) => write_op_skip(self.delta, count)?,
+                        Segment::Hole(count) => write_op_hole(self.delta, count)?,
+                        Segment::Fill(byte, count) => write_op_fill(self.delta, byte, count)?,
+                        Segment::Diff(_) => unreachable!(),
+                    }
+                    segment.len()
+                }
+                Plan::Diff(num) => {
+                    let len = segments.by_ref().take(num).map(Segment::len).sum();
+                    self.diff(file_a, file_b, self.pos, len)?;
+                    len
+                }
+            };
+            self.pos += len;
+        }
+        file_a.seek(SeekFrom::Start(resume_a))?;
+        file_b.seek(SeekFrom::Start(resume_b))?;
+
+        Ok(())
+    }
+
+    fn copy(&mut self, offset: u64, count: u64) -> Result<()> {
+        write_op_copy(self.delta, offset, count)
+    }
+
+    fn copy_b(&mut self, offset: u64, count: u64) -> Result<()> {
+        write_op_copy_b(self.delta, offset, count)
+    }
+
+    /*
+     * Writes OP_ADDs of the differences between file_b and the data at
+     * "offset" in file_a, compressed in records of CDIFF_RECORDLEN if there
+     * is a codec.
+     */
+    fn add(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let codec = match self.compression {
+            Some(codec) => codec,
+            None => {
+                self.delta.write_all(&[OP_ADD])?;
+                write_varint(self.delta, offset)?;
+                return write_op_diff(self.delta, data);
+            }
+        };
+
+        let mut offset = offset;
+        for chunk in data.chunks(CDIFF_RECORDLEN as usize) {
+            self.delta.write_all(&[OP_ADD])?;
+            write_varint(self.delta, offset)?;
+            write_op_cdiff(self.delta, codec, chunk)?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /*
+     * Writes the "num" bytes at "pos" in file_b as a single OP_DIFF, or as
+     * compressed records of CDIFF_RECORDLEN.
+     */
+    fn diff<A: Read + Seek + ?Sized, B: Read + Seek + ?Sized>(&mut self, file_a: &mut A, file_b: &mut B, pos: u64, num: u64) -> Result<()> {
+        if self.xor {
+            return self.xor(file_a, file_b, pos, num);
+        }
+        let codec = match self.compression {
+            Some(codec) => codec,
+            None => {
+                self.delta.write_all(&[OP_DIFF])?;
+                write_varint(self.delta, num)?;
+                return append_data(self.delta, file_b, num, pos);
+            }
+        };
+
+        let mut pos = file_b.seek(SeekFrom::Start(pos))?;
+        let mut buf = Vec::new();
+        let mut left = num;
+        while left > 0 {
+            let len = min(left, CDIFF_RECORDLEN);
+            buf.resize(len as usize, 0);
+            file_b.read_exact(&mut buf)?;
+            if self.dictionary {
+                self.dict_diff(codec, file_a, pos, &buf)?;
+            } else {
+                write_op_cdiff(self.delta, codec, &buf)?;
+            }
+            pos += len;
+            left -= len;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Like diff(), but writes OP_XORs of the data and the data at the same
+     * position in file_a, in records of CDIFF_RECORDLEN.
+     */
+    fn xor<A: Read + Seek + ?Sized, B: Read + Seek + ?Sized>(&mut self, file_a: &mut A, file_b: &mut B, pos: u64, num: u64) -> Result<()> {
+        file_a.seek(SeekFrom::Start(pos))?;
+        file_b.seek(SeekFrom::Start(pos))?;
+        let mut abuf = Vec::new();
+        let mut bbuf = Vec::new();
+        let mut left = num;
+        while left > 0 {
+            let len = min(left, CDIFF_RECORDLEN) as usize;
+            abuf.resize(len, 0);
+            bbuf.resize(len, 0);
+            file_a.read_exact(&mut abuf)?;
+            file_b.read_exact(&mut bbuf)?;
+            for (byte, other) in bbuf.iter_mut().zip(abuf.iter()) {
+                *byte ^= other;
+            }
+
+            self.delta.write_all(&[OP_XOR])?;
+            match self.compression {
+                Some(codec) => write_op_cdiff(self.delta, codec, &bbuf)?,
+                None => write_op_diff(self.delta, &bbuf)?,
+            }
+            left -= len as u64;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Writes the data at "pos" in file_b as an OP_CDIFF_A, using the data
+     * around "pos" in file_a as the dictionary.  In-place, the dictionary can't
+     * start before "pos", as that will have been overwritten.
+     */
+    fn dict_diff<A: Read + Seek + ?Sized>(&mut self, codec: Codec, file_a: &mut A, pos: u64, data: &[u8]) -> Result<()> {
+        let count = data.len() as u64;
+        let margin = count.clamp(MIN_DICT_MARGIN, MAX_DICT_MARGIN);
+        let start = if self.in_place { pos } else { pos.saturating_sub(margin) };
+        let end = min(self.alen, pos + count + margin);
+        if start >= end || count < MIN_DICT_COUNT {
+            // file_a doesn't reach this far
+            return write_op_cdiff(self.delta, codec, data);
+        }
+
+        let resume = file_a.stream_position()?;
+        let mut dict = vec![0u8; (end - start) as usize];
+        file_a.seek(SeekFrom::Start(start))?;
+        file_a.read_exact(&mut dict)?;
+        file_a.seek(SeekFrom::Start(resume))?;
+
+        write_op_cdiff_a(self.delta, codec, start, &dict, data)
+    }
+}
+
+/*
+ * The state for a piece of file_b which follows a record.  Runs which are too
+ * short to be worth a record of their own start as different data, which
+ * becomes a hole or fill if the run continues.
+ */
+fn start_state(same: bool, run: Option<u8>, chunklen: u64, min_run_len: u64) -> State {
+    match run {
+        _ if same => State::Matching(chunklen),
+        Some(0) if chunklen >= min_run_len => State::Hole(chunklen),
+        Some(byte) if chunklen >= min_run_len => State::Fill(chunklen, byte),
+        Some(byte) => State::Different(chunklen, chunklen, byte, 0),
+        None => State::Different(chunklen, 0, 0, 0),
+    }
+}
+
+/*
+ * Returns the value of every byte of chunk, if they are all the same.
+ */
+fn run_of(chunk: &[u8]) -> Option<u8> {
+    let first = *chunk.first()?;
+    if chunk.iter().all(|&byte| byte == first) {
+        Some(first)
+    } else {
+        None
+    }
+}
+
+/*
+ * A piece of file_b, which is the same as file_a if "same" is set, and is a
+ * run of one value if "run" is set.
+ */
+#[derive(Debug, Clone, Copy)]
+struct Piece {
+    same: bool,
+    len: u64,
+    run: Option<u8>,
+}
+
+/*
+ * Appends a piece for chunk, joining it to the last piece if neither is a
+ * run, as next_state() treats them the same either way.  Runs are only looked
+ * for if "runs" is set.
+ */
+fn push_piece(pieces: &mut Vec<Piece>, same: bool, chunk: &[u8], runs: bool) {
+    let run = if runs { run_of(chunk) } else { None };
+    push(pieces, Piece { same, len: chunk.len() as u64, run });
+}
+
+fn push(pieces: &mut Vec<Piece>, piece: Piece) {
+    match pieces.last_mut() {
+        Some(last) if last.same == piece.same && last.run.is_none() && piece.run.is_none() => last.len += piece.len,
+        _ => pieces.push(piece),
+    }
+}
+
+/*
+ * Moves on to the next piece of file_b.  A segment is pushed once the piece
+ * shows that it has ended.
+ */
+fn next_state<W: Write>(state: State, piece: Piece, records: &mut RecordWriter<W>) -> State {
+    let Piece { same, len: chunklen, run } = piece;
+    let min_run_len = records.min_run_len();
+
+    match state {
+        State::Init => {
+            start_state(same, run, chunklen, min_run_len)
+        },
+        State::Matching(num) => {
+            if same {
+                //println!("0same: {:?}", piece);
+                State::Matching(num + chunklen)
+            } else {
+                //println!("0diff: {:?}", piece);
+                records.push(Segment::Skip(num));
+                start_state(same, run, chunklen, min_run_len)
+            }
+        },
+        State::Hole(num) => {
+            if run == Some(0) {
+                State::Hole(num + chunklen)
+            } else {
+                records.push(Segment::Hole(num));
+                start_state(same, run, chunklen, min_run_len)
+            }
+        },
+        State::Fill(num, byte) => {
+            if run == Some(byte) {
+                State::Fill(num + chunklen, byte)
+            } else {
+                records.push(Segment::Fill(byte, num));
+                start_state(same, run, chunklen, min_run_len)
+            }
+        },
+        State::Different(num, trailing, value, matching) => {
+            // the length and value of the trailing run, and the length of the
+            // trailing match, including this piece
+            let (trailing, value) = match run {
+                Some(byte) if byte == value => (trailing + chunklen, byte),
+                Some(byte) => (chunklen, byte),
+                None => (0, value),
+            };
+            let matching = if same { matching + chunklen } else { 0 };
+            if matching >= records.min_skip_len() {
+                //println!("1same: {:?}", piece);
+                // the data from file_b goes in the delta, the trailing match becomes a skip
+                records.push(Segment::Diff(num + chunklen - matching));
+                State::Matching(matching)
+            } else if trailing >= min_run_len {
+                // the trailing run becomes a hole, or a fill
+                let num = num + chunklen - trailing;
+                if num > 0 {
+                    records.push(Segment::Diff(num));
+                }
+                if value == 0 {
+                    State::Hole(trailing)
+                } else {
+                    State::Fill(trailing, value)
+                }
+            } else {
+                //println!("1diff: {:?}", piece);
+                State::Different(num + chunklen, trailing, value, matching)
+            }
+        }
+    }
+}
+
+/*
+ * Pushes the final segment.
+ */
+fn end_state<W: Write>(state: State, records: &mut RecordWriter<W>) {
+    match state {
+        State::Init => {
+            // files were empty
+        },
+        State::Matching(num) => {
+            records.push(Segment::Skip(num));
+        },
+        State::Hole(num) => {
+            records.push(Segment::Hole(num));
+        },
+        State::Fill(num, byte) => {
+            records.push(Segment::Fill(byte, num));
+        },
+        State::Different(num, _, _, matching) => {
+            // the data from file_b goes in the delta, apart from any trailing match
+            records.push(Segment::Diff(num - matching));
+            if matching > 0 {
+                records.push(Segment::Skip(matching));
+            }
+        }
+    }
+}
+
+/*
+ * Returns how many bytes at the start of a and b are the same.  Windows of
+ * bytes are compared at once, which memcmp vectorises, and then words, so
+ * that the first difference is found without comparing byte by byte.
+ */
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    const WINDOW: usize = 256;
+    let len = min(a.len(), b.len());
+    let mut i = 0;
+    while i + WINDOW <= len && a[i..i + WINDOW] == b[i..i + WINDOW] {
+        i += WINDOW;
+    }
+    while i + 8 <= len {
+        let x = u64::from_le_bytes(a[i..i + 8].try_into().unwrap());
+        let y = u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        if x != y {
+            return i + (x ^ y).trailing_zeros() as usize / 8;
+        }
+        i += 8;
+    }
+    i + a[i..len].iter().zip(&b[i..len]).take_while(|(x, y)| x == y).count()
+}
+
+/*
+ * Compares a block of file_b, which is different from the block at the same
+ * position in file_a, if file_a is that long, chunk by chunk.  The bytes at
+ * either end of each chunk which differ are trimmed, so that only the bytes
+ * which differ are different pieces.
+ */
+fn compare_block(ablock: Option<&[u8]>, bblock: &[u8], chunk_size: u64, runs: bool, pieces: &mut Vec<Piece>) {
+    let blocklen = bblock.len();
+    let chunksize = min(chunk_size, blocklen as u64) as usize;
+
+    let mut from = 0;
+    while from < blocklen {
+        let ablock = match ablock {
+            Some(ablock) => ablock,
+            None => {
+                // file_b file is longer - we must copy the excess, apart from any zeros
+                let to = min(from + chunksize, blocklen);
+                push_piece(pieces, false, &bblock[from..to], runs);
+                from = to;
+                continue;
+            }
+        };
+
+        // the chunks before the next difference are the same
+        let same = common_prefix(&ablock[from..], &bblock[from..]) / chunksize * chunksize;
+        for bchunk in bblock[from..from + same].chunks(chunksize) {
+            push_piece(pieces, true, bchunk, runs);
+        }
+        from += same;
+        if from == blocklen {
+            break;
+        }
+
+        let to = min(from + chunksize, blocklen);
+        let achunk = &ablock[from..to];
+        let bchunk = &bblock[from..to];
+        if achunk == bchunk {
+            push_piece(pieces, true, bchunk, runs);
+        } else {
+            let len = bchunk.len();
+            let prefix = common_prefix(achunk, bchunk);
+            let suffix = achunk.iter().rev().zip(bchunk.iter().rev()).take_while(|(x, y)| x == y).count();
+            if prefix > 0 {
+                push_piece(pieces, true, &bchunk[..prefix], runs);
+            }
+            push_piece(pieces, false, &bchunk[prefix..len - suffix], runs);
+            if suffix > 0 {
+                push_piece(pieces, true, &bchunk[len - suffix..], runs);
+            }
+        }
+        from = to;
+    }
+}
+
+/*
+ * A span of file_b, and of file_a at the same position, if file_a is that
+ * long.  Spans are read into buffers, or borrowed if the files are in memory.
+ * Holes are borrowed from a buffer of zeros, and spans which are holes in
+ * both files aren't looked at at all.  Spans which are known to be the same
+ * in both files are only read from file_a.
+ */
+enum Span<'s> {
+    Data {
+        a: Option<Cow<'s, [u8]>>,
+        b: Cow<'s, [u8]>,
+    },
+    Holes(u64),
+    Same(Cow<'s, [u8]>),
+}
+
+/*
+ * Compares a span block by block, returning its pieces in order.
+ */
+fn compare_span(span: &Span, block_size: u64, chunk_size: u64, runs: bool) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    match span {
+        Span::Data { a, b } => {
+            let block_size = block_size as usize;
+            for (i, bblock) in b.chunks(block_size).enumerate() {
+                let ablock = a.as_ref().map(|a| &a[i * block_size..i * block_size + bblock.len()]);
+                if ablock == Some(bblock) {
+                    push_piece(&mut pieces, true, bblock, runs);
+                } else {
+                    compare_block(ablock, bblock, chunk_size, runs, &mut pieces);
+                }
+            }
+        }
+        Span::Same(data) => {
+            // the same pieces as for blocks which have been compared
+            for block in data.chunks(block_size as usize) {
+                push_piece(&mut pieces, true, block, runs);
+            }
+        }
+        Span::Holes(len) => {
+            // the same pieces as for blocks of zeros which have been compared
+            let mut from = 0;
+            while from < *len {
+                let block = min(len - from, block_size);
+                push(&mut pieces, Piece { same: true, len: block, run: if runs { Some(0) } else { None } });
+                from += block;
+            }
+        }
+    }
+    pieces
+}
+
+/*
+ * What write_aligned() knows of the files without reading them: their
+ * contents, if they are in memory, their extents which are data, rather
+ * than holes, and the extents of file_b which are the same as file_a, if
+ * those are known.
+ */
+#[derive(Clone, Copy)]
+struct Known<'s> {
+    memory: Option<(&'s [u8], &'s [u8])>,
+    data: Option<(Extents<'s>, Extents<'s>)>,
+    shared: Option<Extents<'s>>,
+}
+
+impl<'s> Known<'s> {
+    fn in_memory(a: &'s [u8], b: &'s [u8]) -> Known<'s> {
+        Known { memory: Some((a, b)), data: None, shared: None }
+    }
+}
+
+/*
+ * A file which write_aligned() reads.  skip_known() moves past data which is
+ * known to be next in the file, without reading it.
+ */
+trait AlignedFile: Read + Seek {
+    fn skip_known(&mut self, data: &[u8]) -> Result<()> {
+        self.seek(SeekFrom::Current(data.len() as i64))?;
+        Ok(())
+    }
+}
+
+impl AlignedFile for Cursor<&[u8]> {}
+
+impl<R: Read + Seek> AlignedFile for HashReader<R> {
+    // the data is still hashed
+    fn skip_known(&mut self, data: &[u8]) -> Result<()> {
+        HashReader::skip_known(self, data)
+    }
+}
+
+/*
+ * Where write_aligned() gets its spans from: the files, which are positioned
+ * at "pos", or the contents of the files, if they are in memory.  Spans do
+ * not straddle the end of file_a, which is "alen" bytes long.
+ */
+struct Spans<'s> {
+    known: Known<'s>,
+    zeros: &'s [u8], // as long as a span, if the extents which are data are known
+    alen: u64,
+    pos: u64,
+    end: u64,
+    len: u64,   // of each span
+    count: usize, // of spans at once
+}
+
+impl<'s> Spans<'s> {
+    fn read<A: AlignedFile + ?Sized, B: AlignedFile + ?Sized>(&mut self, file_a: &mut A, file_b: &mut B) -> Result<Vec<Span<'s>>> {
+        let mut spans = Vec::new();
+        while spans.len() < self.count && self.pos < self.end {
+            let limit = if self.pos < self.alen { min(self.end, self.alen) } else { self.end };
+            let (from, to) = (self.pos, self.pos + min(limit - self.pos, self.len));
+            let (a_hole, b_hole) = match self.known.data {
+                Some((data_a, data_b)) => (is_hole(data_a, from, to), is_hole(data_b, from, to)),
+                None => (false, false),
+            };
+            let (memory_a, memory_b) = match self.known.memory {
+                Some((a, b)) => (Some(a), Some(b)),
+                None => (None, None),
+            };
+            let span = if from < self.alen && a_hole && b_hole {
+                file_a.seek(SeekFrom::Start(to))?;
+                file_b.seek(SeekFrom::Start(to))?;
+                Span::Holes(to - from)
+            } else if from < self.alen && self.known.shared.is_some_and(|shared| is_within(shared, from, to)) {
+                let a = self.contents(file_a, memory_a, a_hole, from, to)?;
+                file_b.skip_known(&a)?;
+                Span::Same(a)
+            } else {
+                let b = self.contents(file_b, memory_b, b_hole, from, to)?;
+                let a = if from < self.alen { Some(self.contents(file_a, memory_a, a_hole, from, to)?) } else { None };
+                Span::Data { a, b }
+            };
+            spans.push(span);
+            self.pos = to;
+        }
+        Ok(spans)
+    }
+
+    /*
+     * Reads, or borrows, one file's part of a span.  Holes read as zeros, so
+     * they are borrowed from "zeros", and the file is moved past them.
+     */
+    fn contents<F: AlignedFile + ?Sized>(&self, file: &mut F, memory: Option<&'s [u8]>, hole: bool, from: u64, to: u64) -> Result<Cow<'s, [u8]>> {
+        if hole {
+            file.seek(SeekFrom::Start(to))?;
+            return Ok(Cow::Borrowed(&self.zeros[..(to - from) as usize]));
+        }
+        match memory {
+            Some(memory) => {
+                // so that a HashReader hashes it
+                let data = &memory[from as usize..to as usize];
+                file.skip_known(data)?;
+                Ok(Cow::Borrowed(data))
+            }
+            None => {
+                let mut buf = vec![0u8; (to - from) as usize];
+                file.read_exact(&mut buf)?;
+                Ok(Cow::Owned(buf))
+            }
+        }
+    }
+}
+
+/*
+ * Writes records for the bytes of file_b from "start" to "end", by comparing
+ * them with the bytes at the same positions in file_a, which is "alen" bytes
+ * long.  Both files must be positioned at "start".  "known" is what is known
+ * of the files without reading them.
+ *
+ * The files are read in spans, which are compared on "threads" threads while
+ * the next spans are read.  The pieces are then given to next_state() in
+ * order, so the records are the same for any number of threads.
+ */
+fn write_aligned<W: Write, A: AlignedFile + ?Sized, B: AlignedFile + ?Sized>(file_a: &mut A, file_b: &mut B, known: Known, alen: u64, start: u64, end: u64, records: &mut RecordWriter<W>) -> Result<()> {
+    let mut state = State::Init;
+    records.pos = start;
+
+    // spans are whole blocks, so that the blocks are the same as if the files
+    // were compared in one go
+    let (block_size, chunk_size) = (records.block_size, records.chunk_size);
+    let len = max(SPAN_LEN / block_size, 1) * block_size;
+    let zeros = if known.data.is_some() { vec![0u8; len as usize] } else { Vec::new() };
+    let mut spans = Spans {
+        known,
+        zeros: &zeros,
+        alen,
+        pos: start,
+        end,
+        len,
+        count: records.threads,
+    };
+    // OP_HOLEs and OP_FILLs lose what file_a was, so they can't be reversed
+    let runs = !records.xor;
+
+    let mut current = spans.read(file_a, file_b)?;
+    while !current.is_empty() {
+        let (pieces, next) = thread::scope(|scope| -> Result<_> {
+            // spans of holes have nothing to compare, so they don't need a thread
+            let workers: Vec<_> = current.iter()
+                .map(|span| match span {
+                    Span::Data { .. } | Span::Same(_) => Some(scope.spawn(move || compare_span(span, block_size, chunk_size, runs))),
+                    Span::Holes(_) => None,
+                })
+                .collect();
+            let next = spans.read(file_a, file_b)?;
+            let pieces: Vec<Vec<Piece>> = workers.into_iter().zip(&current)
+                .map(|(worker, span)| match worker {
+                    Some(worker) => worker.join().unwrap(),
+                    None => compare_span(span, block_size, chunk_size, runs),
+                })
+                .collect();
+            Ok((pieces, next))
+        })?;
+
+        for pieces in pieces {
+            for piece in pieces {
+                state = next_state(state, piece, records);
+            }
+            records.flush(file_a, file_b, false)?;
+        }
+        current = next;
+    }
+
+    // write final records
+    end_state(state, records);
+    records.flush(file_a, file_b, true)
+}
+
+/*
+ * Writes records for the whole of b, using OP_COPY for data which has moved,
+ * OP_COPY_B for data which is repeated, and comparing everything else with
+ * the same positions in a.
+ */
+fn write_moves<W: Write>(a: &[u8], b: &[u8], in_place: bool, records: &mut RecordWriter<W>) -> Result<()> {
+    let alen = a.len() as u64;
+    let blen = b.len() as u64;
+    let mut file_a = Cursor::new(a);
+    let mut file_b = Cursor::new(b);
+
+    let mut pos = 0;
+    for m in find_moves(a, b, in_place) {
+        if m.pos > pos {
+            file_a.set_position(pos);
+            file_b.set_position(pos);
+            write_aligned(&mut file_a, &mut file_b, Known::in_memory(a, b), alen, pos, m.pos, records)?;
+        }
+        if m.from_b {
+            records.copy_b(m.offset, m.len)?;
+        } else {
+            records.copy(m.offset, m.len)?;
+        }
+        pos = m.pos + m.len;
+    }
+    if blen > pos {
+        file_a.set_position(pos);
+        file_b.set_position(pos);
+        write_aligned(&mut file_a, &mut file_b, Known::in_memory(a, b), alen, pos, blen, records)?;
+    }
+    Ok(())
+}
+
+/*
+ * Filters the code in b, and in a if it is for the same machine, returning
+ * the filter and the regions which were filtered, if b is an ELF file which
+ * can be filtered.
+ */
+fn filter_files(a: &mut [u8], b: &mut [u8]) -> Option<(Filter, Vec<Region>, Vec<Region>)> {
+    let (filter, regions_b) = detect(b)?;
+    let regions_a = match detect(a) {
+        Some((filter_a, regions_a)) if filter_a == filter => regions_a,
+        _ => Vec::new(),
+    };
+    for (data, regions) in [(a, &regions_a), (b, &regions_b)] {
+        for region in regions.iter() {
+            filter.encode(&mut data[region.offset as usize..(region.offset + region.len) as usize], region.offset);
+        }
+    }
+    Some((filter, regions_a, regions_b))
+}
+
+/*
+ * Writes an OP_ADD for the "len" bytes at "pos" in b, which are similar to
+ * those at "offset" in a.
+ */
+fn write_add<W: Write>(a: &[u8], b: &[u8], pos: u64, offset: u64, len: u64, records: &mut RecordWriter<W>) -> Result<()> {
+    let from = &a[offset as usize..(offset + len) as usize];
+    let to = &b[pos as usize..(pos + len) as usize];
+    let diffs: Vec<u8> = to.iter().zip(from.iter()).map(|(x, y)| x.wrapping_sub(*y)).collect();
+    records.add(offset, &diffs)
+}
+
+/*
+ * Writes records for the whole of b, using OP_ADD for data which is similar
+ * to data in a, and comparing everything else with the same positions in a.
+ */
+fn write_approx<W: Write>(a: &[u8], b: &[u8], in_place: bool, records: &mut RecordWriter<W>) -> Result<()> {
+    let alen = a.len() as u64;
+    let blen = b.len() as u64;
+    let mut file_a = Cursor::new(a);
+    let mut file_b = Cursor::new(b);
+
+    let mut pos = 0;
+    for add in find_adds(a, b) {
+        if in_place && add.offset < add.pos {
+            // that data will have been overwritten
+            continue;
+        }
+        if add.pos > pos {
+            file_a.set_position(pos);
+            file_b.set_position(pos);
+            write_aligned(&mut file_a, &mut file_b, Known::in_memory(a, b), alen, pos, add.pos, records)?;
+        }
+
+        // long runs of zeros are left out, to become OP_HOLEs
+        let end = add.pos + add.len;
+        let mut start = add.pos;
+        let mut zeros = add.pos;
+        while zeros < end {
+            if b[zeros as usize] != 0 {
+                zeros += 1;
+                continue;
+            }
+            let run = b[zeros as usize..end as usize].iter().take_while(|&&byte| byte == 0).count() as u64;
+            if run >= MIN_ADD_HOLE_LEN {
+                if zeros > start {
+                    write_add(a, b, start, add.offset + (start - add.pos), zeros - start, records)?;
+                }
+                file_a.set_position(zeros);
+                file_b.set_position(zeros);
+                write_aligned(&mut file_a, &mut file_b, Known::in_memory(a, b), alen, zeros, zeros + run, records)?;
+                start = zeros + run;
+            }
+            zeros += run;
+        }
+        if end > start {
+            write_add(a, b, start, add.offset + (start - add.pos), end - start, records)?;
+        }
+        pos = end;
+    }
+    if blen > pos {
+        file_a.set_position(pos);
+        file_b.set_position(pos);
+        write_aligned(&mut file_a, &mut file_b, Known::in_memory(a, b), alen, pos, blen, records)?;
+    }
+    Ok(())
+}
+
+impl<'a, A: Read + Seek + ?Sized, B: Read + Seek + ?Sized> DeltaWriter<'a, A, B> {
+    pub fn new(file_a: &'a mut A, file_b: &'a mut B) -> DeltaWriter<'a, A, B> {
+        DeltaWriter::with_options(file_a, file_b, WriteOptions::default())
+    }
+
+    pub fn with_options(file_a: &'a mut A, file_b: &'a mut B, options: WriteOptions) -> DeltaWriter<'a, A, B> {
+        DeltaWriter { file_a, file_b, options, memory: None, extents: None, shared: None }
+    }
+
+    /*
+     * Gives the contents of file_a and file_b, such as when they have been
+     * memory-mapped, so that they are compared without being read.
+     */
+    pub fn with_memory(mut self, a: &'a [u8], b: &'a [u8]) -> DeltaWriter<'a, A, B> {
+        self.memory = Some((a, b));
+        self
+    }
+
+    /*
+     * Gives the extents of file_a and file_b which are data, rather than
+     * holes, such as from data_extents().  Spans which are holes in both files
+     * are neither read nor compared, and holes are hashed without being read.
+     */
+    pub fn with_extents(mut self, a: &'a [Range<u64>], b: &'a [Range<u64>]) -> DeltaWriter<'a, A, B> {
+        self.extents = Some((a, b));
+        self
+    }
+
+    /*
+     * Gives the extents of file_b which are the same as file_a at the same
+     * positions, such as from shared_extents(), in order.  Spans within them
+     * aren't compared, and are only read from file_a, which is hashed as
+     * file_b's data as well.
+     */
+    pub fn with_shared(mut self, shared: &'a [Range<u64>]) -> DeltaWriter<'a, A, B> {
+        self.shared = Some(shared);
+        self
+    }
+
+    /*
+     * Writes the complete delta, from magic to OP_END, to "delta", as the
+     * records are found.  OP_HASH_A comes before the records, so file_a is
+     * hashed first, which reads it an extra time.  write_seekable_delta()
+     * hashes it as it is compared instead.
+     */
+    pub fn write_delta<W: Write>(&mut self, delta: &mut W) -> Result<()> {
+        let (alen, blen) = self.check()?;
+        let hash_a = self.hash_a(alen)?;
+
+        write_magic(delta)?;
+        write_op_ver(delta, version())?;
+        write_op_len_a(delta, alen)?;
+        write_op_hash_a(delta, hash_a)?;
+        let (_, hash_b) = self.write_body(delta, alen, blen, Some(hash_a))?;
+        write_op_len_b(delta, blen)?;
+        write_op_hash_b(delta, hash_b)?;
+        write_op_end(delta)?;
+
+        Result::Ok(())
+    }
+
+    /*
+     * Like write_delta(), but OP_HASH_A is written as zeros at first, and
+     * filled in once file_a has been hashed.
+     */
+    pub fn write_seekable_delta<W: Write + Seek>(&mut self, delta: &mut W) -> Result<()> {
+        let (alen, blen) = self.check()?;
+
+        write_magic(delta)?;
+        write_op_ver(delta, version())?;
+        write_op_len_a(delta, alen)?;
+        let at = delta.stream_position()?;
+        write_op_hash_a(delta, [0u8; 32])?;
+        let (hash_a, hash_b) = self.write_body(delta, alen, blen, None)?;
+        write_op_len_b(delta, blen)?;
+        write_op_hash_b(delta, hash_b)?;
+        write_op_end(delta)?;
+
+        let end = delta.stream_position()?;
+        delta.seek(SeekFrom::Start(at))?;
+        write_op_hash_a(delta, hash_a)?;
+        delta.seek(SeekFrom::Start(end))?;
+
+        Result::Ok(())
+    }
+
+    /*
+     * Hashes file_a, or its contents, if they are in memory.
+     */
+    fn hash_a(&mut self, alen: u64) -> Result<[u8; 32]> {
+        let threads = self.options.threads;
+        match (self.memory, self.extents) {
+            (Some((a, _)), Some((data_a, _))) => hash_sparse_file(&mut Cursor::new(a), alen, threads, data_a),
+            (Some((a, _)), None) => Ok(hash_data(a, threads)),
+            (None, Some((data_a, _))) => hash_sparse_file(self.file_a, alen, threads, data_a),
+            (None, None) => hash_file_with_threads(self.file_a, alen, threads),
+        }
+    }
+
+    /*
+     * Checks the options, returning the lengths of file_a and file_b.
+     */
+    fn check(&mut self) -> Result<(u64, u64)> {
+        let alen = stream_len(self.file_a)?;
+        let blen = stream_len(self.file_b)?;
+
+        if self.options.block_size == 0 || self.options.chunk_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "The block and chunk sizes must not be zero."));
+        }
+        if self.options.block_size > MAX_BLOCK_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "The block size must not be more than 4 MiB."));
+        }
+        if self.options.threads == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "The number of threads must not be zero."));
+        }
+        if self.options.xor {
+            if alen != blen {
+                return Err(Error::new(ErrorKind::InvalidInput, "An XOR delta needs file_a and file_b to be the same length."));
+            }
+            if self.options.moves || self.options.approx || self.options.filter || self.options.dictionary {
+                return Err(Error::new(ErrorKind::InvalidInput, "An XOR delta can't use moves, approx, filter or a dictionary."));
+            }
+        }
+        if let Some((a, b)) = self.memory {
+            if a.len() as u64 != alen || b.len() as u64 != blen {
+                return Err(Error::new(ErrorKind::InvalidInput, "The contents in memory must be the same length as the files."));
+            }
+        }
+        Ok((alen, blen))
+    }
+
+    /*
+     * Writes the records which describe file_b, after OP_HASH_A, returning the
+     * hashes of file_a and file_b.  Each file is only read once, and hashed as
+     * it is read, apart from any of file_a beyond the end of file_b.  file_a
+     * isn't hashed again if its hash, "hash_a", is already known.
+     */
+    fn write_body<W: Write>(&mut self, delta: &mut W, alen: u64, blen: u64, hash_a: Option<[u8; 32]>) -> Result<([u8; 32], [u8; 32])> {
+        self.file_a.seek(SeekFrom::Start(0))?;
+        self.file_b.seek(SeekFrom::Start(0))?;
+        let threads = self.options.threads;
+        let mut file_a = match hash_a {
+            Some(_) => HashReader::unhashed(&mut *self.file_a),
+            None => HashReader::new(&mut *self.file_a, threads, self.extents.map(|(a, _)| a.to_vec())),
+        };
+        let mut file_b = HashReader::new(&mut *self.file_b, threads, self.extents.map(|(_, b)| b.to_vec()));
+        // contents in memory which aren't compared span by span are hashed
+        // first, as that is the only time they are read in order
+        let in_memory = self.options.approx || self.options.moves || self.options.filter;
+        let extents = self.extents;
+        let hash_memory = |data: &[u8], len: u64, extents: Option<Extents>| match extents {
+            // holes are hashed without touching their pages
+            Some(extents) => hash_sparse_file(&mut Cursor::new(data), len, threads, extents),
+            None => Ok(hash_data(data, threads)),
+        };
+        let hashed = match self.memory {
+            Some((a, b)) if in_memory => Some((hash_a.map_or_else(|| hash_memory(a, alen, extents.map(|(a, _)| a)), Ok)?,
+                                               hash_memory(b, blen, extents.map(|(_, b)| b))?)),
+            _ => None,
+        };
+
+        // filtering modifies the data, so it needs its own copy
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        let mut memory = self.memory;
+        if self.options.filter || (in_memory && memory.is_none()) {
+            a.reserve(alen as usize);
+            b.reserve(blen as usize);
+            match memory {
+                Some((ma, mb)) => {
+                    a.extend_from_slice(ma);
+                    b.extend_from_slice(mb);
+                }
+                None => {
+                    file_a.read_to_end(&mut a)?;
+                    file_b.read_to_end(&mut b)?;
+                }
+            }
+            if self.options.filter {
+                if let Some((filter, regions_a, regions_b)) = filter_files(&mut a, &mut b) {
+                    write_op_filter(delta, filter, &regions_a, &regions_b)?;
+                }
+            }
+            memory = Some((&a, &b));
+        }
+
+        // filtering may change holes, and shared data, so they are compared
+        // like anything else
+        let (data, shared) = if self.options.filter { (None, None) } else { (self.extents, self.shared) };
+        let mut records = RecordWriter {
+            delta: &mut *delta,
+            compression: self.options.compression,
+            dictionary: self.options.dictionary,
+            in_place: self.options.in_place,
+            xor: self.options.xor,
+            alen,
+            block_size: self.options.block_size,
+            chunk_size: self.options.chunk_size,
+            threads: self.options.threads,
+            pending: Vec::new(),
+            pos: 0,
+        };
+        let hashes = match memory {
+            Some((a, b)) if self.options.approx => {
+                write_approx(a, b, self.options.in_place, &mut records)?;
+                hashed
+            }
+            Some((a, b)) if self.options.moves => {
+                write_moves(a, b, self.options.in_place, &mut records)?;
+                hashed
+            }
+            Some((a, b)) if self.options.filter => {
+                write_aligned(&mut Cursor::new(a), &mut Cursor::new(b), Known { memory, data, shared }, alen, 0, blen, &mut records)?;
+                hashed
+            }
+            Some((a, b)) => {
+                // the contents are hashed as their spans are compared
+                let mut memory_a = match hash_a {
+                    Some(_) => HashReader::unhashed(Cursor::new(a)),
+                    None => HashReader::new(Cursor::new(a), threads, data.map(|(a, _)| a.to_vec())),
+                };
+                let mut memory_b = HashReader::new(Cursor::new(b), threads, data.map(|(_, b)| b.to_vec()));
+                write_aligned(&mut memory_a, &mut memory_b, Known { memory, data, shared }, alen, 0, blen, &mut records)?;
+                Some((memory_a.finish(alen)?, memory_b.finish(blen)?))
+            }
+            None => {
+                write_aligned(&mut file_a, &mut file_b, Known { memory: None, data, shared }, alen, 0, blen, &mut records)?;
+                None
+            }
+        };
+
+        let (found_a, found_b) = match hashes {
+            Some(hashes) => hashes,
+            None => (file_a.finish(alen)?, file_b.finish(blen)?),
+        };
+        Ok((hash_a.unwrap_or(found_a), found_b))
+    }
+}
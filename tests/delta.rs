@@ -0,0 +1,1020 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use vsdelta::built_info;
+use vsdelta::common::*;
+use vsdelta::*;
+
+/*
+ * Opens a new file in the temporary directory, holding "data", positioned at
+ * its start.  The file is removed straight away, and is gone once it is
+ * closed.
+ */
+fn temp_file(data: &[u8]) -> File {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("vsdelta-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst));
+    let path = std::env::temp_dir().join(name);
+    let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file.write_all(data).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file
+}
+
+fn contents(file: &mut File) -> Vec<u8> {
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut data).unwrap();
+    data
+}
+
+fn write_delta(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut delta = temp_file(&[]);
+    DeltaWriter::new(&mut temp_file(a), &mut temp_file(b)).write_delta(&mut delta).unwrap();
+    contents(&mut delta)
+}
+
+fn write_delta_with(a: &[u8], b: &[u8], options: WriteOptions) -> Vec<u8> {
+    let mut delta = Vec::new();
+    DeltaWriter::with_options(&mut Cursor::new(a), &mut Cursor::new(b), options).write_delta(&mut delta).unwrap();
+    delta
+}
+
+fn apply(a: &[u8], delta: &[u8], options: &ApplyOptions) -> Vec<u8> {
+    let mut output = Cursor::new(Vec::new());
+    apply_to(&mut Cursor::new(a), delta, &mut output, options).unwrap();
+    output.into_inner()
+}
+
+#[test]
+fn writes_skips_and_diffs() {
+    let a = b"0123456789abcdefghijklmnopqrstuv";
+    let b = b"0123456789abcdefGHIJKLMNopqrstuvwxyz";
+
+    let mut expected = b"vsdelta".to_vec();
+    expected.push(OP_VER);
+    for part in [built_info::PKG_VERSION_MAJOR, built_info::PKG_VERSION_MINOR, built_info::PKG_VERSION_PATCH] {
+        expected.push(part.parse().unwrap());
+    }
+    // the counts are varints, which are one byte each here
+    expected.extend([OP_LEN_A, 32]);
+    expected.push(OP_HASH_A);
+    expected.extend(blake3::hash(a).as_bytes());
+    expected.extend([OP_SKIP, 16, OP_DIFF, 8]);
+    expected.extend(b"GHIJKLMN");
+    expected.extend([OP_SKIP, 8, OP_DIFF, 4]);
+    expected.extend(b"wxyz");
+    expected.extend([OP_LEN_B, 36]);
+    expected.push(OP_HASH_B);
+    expected.extend(blake3::hash(b).as_bytes());
+    expected.push(OP_END);
+
+    assert!(write_delta(a, b) == expected);
+}
+
+#[test]
+fn reads_ops() {
+    let a = b"0123456789abcdefghijklmnopqrstuv";
+    let b = b"0123456789abcdefGHIJKLMNopqrstuvwxyz";
+    let delta = write_delta(a, b);
+
+    let mut reader = DeltaReader::new(&delta[..]).unwrap();
+    assert!(matches!(reader.next_op().unwrap(), Op::Ver(_)));
+    assert!(matches!(reader.next_op().unwrap(), Op::LenA(32)));
+    assert!(matches!(reader.next_op().unwrap(), Op::HashA(hash) if hash == *blake3::hash(a).as_bytes()));
+    assert!(matches!(reader.next_op().unwrap(), Op::Skip(16)));
+    match reader.next_op().unwrap() {
+        Op::Diff(mut payload) => {
+            assert_eq!(payload.len(), 8);
+            let mut data = Vec::new();
+            payload.read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"GHIJKLMN");
+        }
+        op => panic!("{:?}", op),
+    }
+    assert!(matches!(reader.next_op().unwrap(), Op::Skip(8)));
+    // data which isn't read is skipped
+    assert!(matches!(reader.next_op().unwrap(), Op::Diff(payload) if payload.len() == 4));
+    assert!(matches!(reader.next_op().unwrap(), Op::LenB(36)));
+    assert!(matches!(reader.next_op().unwrap(), Op::HashB(hash) if hash == *blake3::hash(b).as_bytes()));
+    assert!(matches!(reader.next_op().unwrap(), Op::End));
+}
+
+#[test]
+fn rejects_bad_deltas() {
+    assert!(matches!(DeltaReader::new(&b"vsdeltX"[..]), Err(VsError::BadMagic)));
+    assert!(DeltaReader::new(&b"vsdel"[..]).is_err());
+
+    let delta = write_delta(b"0123456789abcdef", b"0123456789ABCDEF");
+    // an unknown opcode, and a delta which ends within OP_DIFF data
+    let mut unknown = delta.clone();
+    unknown[7] = 0x12;
+    assert!(matches!(DeltaReader::new(&unknown[..]).unwrap().next_op(), Err(VsError::UnknownOpcode(0x12))));
+    let truncated = &delta[..delta.iter().position(|&byte| byte == OP_DIFF).unwrap() + 12];
+    let mut reader = DeltaReader::new(truncated).unwrap();
+    loop {
+        match reader.next_op() {
+            Ok(Op::End) => panic!("the delta is truncated"),
+            Ok(_) => {}
+            Err(e) => {
+                assert!(matches!(e, VsError::Truncated));
+                break;
+            }
+        }
+    }
+}
+
+/*
+ * Applies a delta from a to b both ways, checking that each gives b.
+ */
+fn check_apply(a: &[u8], b: &[u8], options: &ApplyOptions) {
+    let mut delta = temp_file(&write_delta(a, b));
+    let mut target = temp_file(a);
+    apply_in_place(&mut target, &mut delta, options).unwrap();
+    assert!(contents(&mut target) == b);
+
+    delta.seek(SeekFrom::Start(0)).unwrap();
+    let mut output = temp_file(&[]);
+    apply_to(&mut temp_file(a), &mut delta, &mut output, options).unwrap();
+    assert!(contents(&mut output) == b);
+}
+
+#[test]
+fn applies() {
+    let a: Vec<u8> = (0..100000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut b = a.clone();
+    b[10..20].fill(b'x');
+    b[50000..70000].fill(0);
+    b.truncate(90000);
+    for sparse in [false, true] {
+        check_apply(&a, &b, &ApplyOptions { sparse, verify: true, ..ApplyOptions::default() });
+        check_apply(&a, &b, &ApplyOptions { sparse, verify: false, ..ApplyOptions::default() });
+    }
+    let mut longer = a.clone();
+    longer.extend(b"and some more");
+    check_apply(&a, &longer, &ApplyOptions::default());
+}
+
+#[test]
+fn in_place_checks_file_a() {
+    let a = b"0123456789abcdefghijklmnopqrstuv";
+    let b = b"0123456789abcdefGHIJKLMNopqrstuvwxyz";
+    let mut delta = temp_file(&write_delta(a, b));
+
+    // even without verifying, the wrong file isn't modified
+    let wrong = b"0123456789ABCDEFghijklmnopqrstuv";
+    let mut target = temp_file(wrong);
+    let options = ApplyOptions { verify: false, ..ApplyOptions::default() };
+    assert!(matches!(apply_in_place(&mut target, &mut delta, &options), Err(VsError::BaseHashMismatch { .. })));
+    assert!(contents(&mut target) == wrong);
+}
+
+#[test]
+fn rejects_the_wrong_files() {
+    let a = b"0123456789abcdefghijklmnopqrstuv";
+    let b = b"0123456789abcdefGHIJKLMNopqrstuvwxyz";
+    let mut delta = temp_file(&write_delta(a, b));
+    let options = ApplyOptions::default();
+
+    let result = apply_to(&mut temp_file(b"0123456789"), &mut delta, &mut temp_file(&[]), &options);
+    assert!(matches!(result, Err(VsError::BaseLengthMismatch { expected: 32, actual: 10 })));
+
+    delta.seek(SeekFrom::Start(0)).unwrap();
+    let wrong = b"0123456789abcdefghijklmnopqrstuV";
+    let result = apply_to(&mut temp_file(wrong), &mut delta, &mut temp_file(&[]), &options);
+    assert!(matches!(result, Err(VsError::BaseHashMismatch { actual, .. }) if actual == *blake3::hash(wrong).as_bytes()));
+}
+
+#[test]
+fn applies_in_memory() {
+    let a: Vec<u8> = (0..50000u32).map(|i| (i * 13 % 241) as u8).collect();
+    let mut b = a.clone();
+    b[1000..1100].fill(b'y');
+    b.extend(b"the end");
+
+    let mut delta = Vec::new();
+    DeltaWriter::new(&mut Cursor::new(&a), &mut Cursor::new(&b)).write_delta(&mut delta).unwrap();
+    assert!(delta == write_delta(&a, &b));
+
+    let mut target = Cursor::new(a.clone());
+    apply_in_place(&mut target, &delta[..], &ApplyOptions::default()).unwrap();
+    assert!(target.into_inner() == b);
+
+    let mut output = Cursor::new(Vec::new());
+    apply_to(&mut Cursor::new(&a), &delta[..], &mut output, &ApplyOptions::default()).unwrap();
+    assert!(output.into_inner() == b);
+}
+
+/*
+ * Gives the count of each op in the delta for which "count" gives one.
+ */
+fn counts(delta: &[u8], count: fn(&Op<&[u8]>) -> Option<u64>) -> Vec<u64> {
+    let mut reader = DeltaReader::new(delta).unwrap();
+    let mut counts = Vec::new();
+    loop {
+        match reader.next_op().unwrap() {
+            Op::End => return counts,
+            op => counts.extend(count(&op)),
+        }
+    }
+}
+
+#[test]
+fn holes() {
+    let a: Vec<u8> = (0..100000u32).map(|i| (i * 7 % 251) as u8 | 1).collect();
+    let mut b = a.clone();
+    b[20000..60000].fill(0);
+    b.extend([0; 30000]);
+
+    let delta = write_delta(&a, &b);
+    let holes = counts(&delta, |op| match op {
+        Op::Hole(count) => Some(*count),
+        _ => None,
+    });
+    assert!(holes == [40000, 30000]);
+    for sparse in [false, true] {
+        check_apply(&a, &b, &ApplyOptions { sparse, verify: true, ..ApplyOptions::default() });
+    }
+}
+
+#[test]
+fn fixed_width_counts() {
+    // a delta from before VARINT_VERSION, whose counts are 8 bytes
+    let a = b"0123456789abcdef";
+    let b = b"0123456789ABCDEF";
+    let mut delta = b"vsdelta".to_vec();
+    delta.extend([OP_VER, 0, 9, 0, OP_LEN_A]);
+    delta.extend(u64tou8ale(16));
+    delta.push(OP_HASH_A);
+    delta.extend(blake3::hash(a).as_bytes());
+    delta.push(OP_SKIP);
+    delta.extend(u64tou8ale(10));
+    delta.push(OP_DIFF);
+    delta.extend(u64tou8ale(6));
+    delta.extend(b"ABCDEF");
+    delta.push(OP_LEN_B);
+    delta.extend(u64tou8ale(16));
+    delta.push(OP_HASH_B);
+    delta.extend(blake3::hash(b).as_bytes());
+    delta.push(OP_END);
+
+    let mut target = Cursor::new(a.to_vec());
+    apply_in_place(&mut target, &delta[..], &ApplyOptions::default()).unwrap();
+    assert!(target.into_inner() == b);
+}
+
+/*
+ * A xorshift generator, so that the tests are repeatable.
+ */
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 { 0 } else { self.next() % n }
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+
+    /*
+     * Zeros, 0xFF bytes, repeating text or random bytes, by kind.
+     */
+    fn run(&mut self, len: usize, kind: u64) -> Vec<u8> {
+        match kind {
+            0 => vec![0; len],
+            1 => vec![0xFF; len],
+            2 => (0..len).map(|i| (i % 7) as u8 + b'a').collect(),
+            _ => self.bytes(len),
+        }
+    }
+
+    /*
+     * A file_a, of random bytes with a run of zeros, 0xFF bytes or text.
+     */
+    fn file_a(&mut self) -> Vec<u8> {
+        let len = self.below(20000) as usize;
+        let mut a = self.bytes(len);
+        let kind = self.below(4);
+        let run = self.run(len / 2, kind);
+        let at = self.below((len - run.len()) as u64 + 1) as usize;
+        a[at..at + run.len()].copy_from_slice(&run);
+        a
+    }
+
+    /*
+     * file_b, as file_a with a few insertions, deletions, changes and copies.
+     */
+    fn file_b(&mut self, a: &[u8]) -> Vec<u8> {
+        let mut b = a.to_vec();
+        for _ in 0..self.below(8) {
+            let pos = self.below(b.len() as u64 + 1) as usize;
+            let n = self.below(3000) as usize + 1;
+            let end = (pos + n).min(b.len());
+            let kind = self.below(4);
+            match self.below(6) {
+                0 => drop(b.splice(pos..pos, self.run(n, kind))),
+                1 => drop(b.drain(pos..end)),
+                2 => drop(b.splice(pos..end, self.run(end - pos, kind))),
+                3 => {
+                    let from = self.below(a.len() as u64 + 1) as usize;
+                    let copied = a[from..(from + n).min(a.len())].to_vec();
+                    drop(b.splice(pos..pos, copied));
+                }
+                4 => b.truncate(pos),
+                _ => if pos < b.len() { b[pos] = b[pos].wrapping_add(1 + self.below(255) as u8) },
+            }
+        }
+        b
+    }
+}
+
+#[test]
+fn write_options() {
+    let options = [
+        WriteOptions::default(),
+        WriteOptions { moves: true, ..WriteOptions::default() },
+        WriteOptions { moves: true, in_place: true, ..WriteOptions::default() },
+        WriteOptions { compression: Some(Codec::Zstd), ..WriteOptions::default() },
+        WriteOptions { moves: true, compression: Some(Codec::Lz4), ..WriteOptions::default() },
+        WriteOptions { in_place: true, compression: Some(Codec::Zstd), dictionary: true, ..WriteOptions::default() },
+        WriteOptions { approx: true, ..WriteOptions::default() },
+        WriteOptions { approx: true, filter: true, in_place: true, ..WriteOptions::default() },
+        WriteOptions { approx: true, in_place: true, compression: Some(Codec::Lz4), dictionary: true, ..WriteOptions::default() },
+        WriteOptions { chunk_size: 1, block_size: 100, ..WriteOptions::default() },
+        WriteOptions { chunk_size: 7, block_size: 3, compression: Some(Codec::Zstd), ..WriteOptions::default() },
+    ];
+    let mut random = Random(0x9E3779B97F4A7C15);
+    for _ in 0..30 {
+        let a = random.file_a();
+        let b = random.file_b(&a);
+        for options in &options {
+            let delta = write_delta_with(&a, &b, options.clone());
+            assert!(apply(&a, &delta, &ApplyOptions::default()) == b, "{:?}", options);
+            let dense = ApplyOptions { sparse: false, ..ApplyOptions::default() };
+            assert!(apply(&a, &delta, &dense) == b, "not sparse {:?}", options);
+
+            // the delta is the same however it is written
+            let threaded = WriteOptions { threads: 3, ..options.clone() };
+            assert!(write_delta_with(&a, &b, threaded.clone()) == delta, "threads {:?}", options);
+            let mut memory = Vec::new();
+            DeltaWriter::with_options(&mut Cursor::new(&a[..]), &mut Cursor::new(&b[..]), threaded)
+                .with_memory(&a, &b)
+                .write_delta(&mut memory)
+                .unwrap();
+            assert!(memory == delta, "memory {:?}", options);
+            let mut seekable = Cursor::new(Vec::new());
+            DeltaWriter::with_options(&mut Cursor::new(&a[..]), &mut Cursor::new(&b[..]), options.clone())
+                .write_seekable_delta(&mut seekable)
+                .unwrap();
+            assert!(seekable.into_inner() == delta, "seekable {:?}", options);
+
+            if options.in_place {
+                check_in_place(&delta[..]).unwrap();
+            }
+            if check_in_place(&delta[..]).is_ok() {
+                let mut target = Cursor::new(a.clone());
+                apply_in_place(&mut target, &delta[..], &ApplyOptions::default()).unwrap();
+                assert!(target.into_inner() == b, "in-place {:?}", options);
+            }
+
+            let mut optimised = Vec::new();
+            optimise_delta(&mut Cursor::new(&a[..]), &delta[..], &mut optimised).unwrap();
+            assert!(apply(&a, &optimised, &ApplyOptions::default()) == b, "optimised {:?}", options);
+            if options.compression.is_none() {
+                assert!(optimised.len() <= delta.len(), "optimised {:?}", options);
+            }
+        }
+    }
+}
+
+#[test]
+fn copies_moved_data() {
+    let mut random = Random(0x9E3779B97F4A7C15);
+    let a = random.bytes(200000);
+    // an insertion moves the rest of the file forwards, a deletion backwards
+    let mut inserted = a[..50000].to_vec();
+    inserted.extend(random.bytes(1000));
+    inserted.extend(&a[50000..]);
+    let mut deleted = a[..50000].to_vec();
+    deleted.extend(&a[51000..]);
+
+    let copied = |op: &Op<&[u8]>| match op {
+        Op::Copy { count, .. } => Some(*count),
+        _ => None,
+    };
+    for (b, in_place) in [(&inserted, false), (&deleted, false), (&deleted, true)] {
+        let delta = write_delta_with(&a, b, WriteOptions { moves: true, in_place, ..WriteOptions::default() });
+        assert!(counts(&delta, copied).iter().sum::<u64>() >= 140000);
+        assert!(delta.len() < 10000);
+        assert!(apply(&a, &delta, &ApplyOptions::default()) == *b);
+        if in_place {
+            let mut target = Cursor::new(a.clone());
+            apply_in_place(&mut target, &delta[..], &ApplyOptions::default()).unwrap();
+            assert!(target.into_inner() == *b);
+        }
+    }
+
+    // data which has moved forwards would be overwritten before it is copied
+    let delta = write_delta_with(&a, &inserted, WriteOptions { moves: true, in_place: false, ..WriteOptions::default() });
+    let result = apply_in_place(&mut Cursor::new(a.clone()), &delta[..], &ApplyOptions::default());
+    assert!(matches!(result, Err(VsError::NotInPlace)));
+    let delta = write_delta_with(&a, &inserted, WriteOptions { moves: true, in_place: true, ..WriteOptions::default() });
+    assert!(counts(&delta, copied).is_empty());
+    // without moves, the rest of the file is different
+    assert!(write_delta(&a, &inserted).len() > 140000);
+}
+
+#[test]
+fn moves_over_zeros() {
+    // a large run of zeros over data is a hole, and is quick to pass over,
+    // even though every window of it matches the zeros at the end of file_a
+    let mut random = Random(0xD1B54A32D192ED03);
+    let mut a = random.bytes(8 * 1024 * 1024);
+    a[7 * 1024 * 1024..].fill(0);
+    let mut b = vec![0; 8 * 1024 * 1024];
+    b.extend(&a[..1024 * 1024]);
+
+    let delta = write_delta_with(&a, &b, WriteOptions { moves: true, in_place: false, ..WriteOptions::default() });
+    let holes = counts(&delta, |op| match op {
+        Op::Hole(count) => Some(*count),
+        _ => None,
+    });
+    assert!(holes == [8 * 1024 * 1024]);
+    assert!(delta.len() < 1000);
+    assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+}
+
+#[test]
+fn copies_repeated_data() {
+    let mut random = Random(0xBF58476D1CE4E5B9);
+    let a = random.bytes(100000);
+    // new data, which is then repeated
+    let mut b = a[..20000].to_vec();
+    let new = random.bytes(30000);
+    b.extend(&new);
+    b.extend(&new);
+    b.extend(&a[80000..]);
+
+    for in_place in [false, true] {
+        let delta = write_delta_with(&a, &b, WriteOptions { moves: true, in_place, ..WriteOptions::default() });
+        let copied = counts(&delta, |op| match op {
+            Op::CopyB { count, .. } => Some(*count),
+            _ => None,
+        });
+        assert!(copied.iter().sum::<u64>() >= 29000);
+        assert!(delta.len() < 35000);
+        assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+        let mut target = Cursor::new(a.clone());
+        apply_in_place(&mut target, &delta[..], &ApplyOptions::default()).unwrap();
+        assert!(target.into_inner() == b);
+    }
+}
+
+/*
+ * Gives the codec of each OP_DIFF or OP_CDIFF in the delta, checking that its
+ * data is "data".
+ */
+fn codecs(delta: &[u8], data: &[u8]) -> Vec<Option<Codec>> {
+    let mut reader = DeltaReader::new(delta).unwrap();
+    let mut codecs = Vec::new();
+    loop {
+        match reader.next_op().unwrap() {
+            Op::Diff(mut payload) => {
+                let mut read = Vec::new();
+                payload.read_to_end(&mut read).unwrap();
+                assert!(read == data);
+                codecs.push(payload.codec());
+            }
+            Op::End => return codecs,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn compresses_diffs() {
+    let mut random = Random(0x94D049BB133111EB);
+    let a = random.bytes(300000);
+    let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog. ".iter().cycle().take(100000).copied().collect();
+    let noise = random.bytes(100000);
+
+    for codec in [Codec::Zstd, Codec::Lz4] {
+        // data which doesn't compress is written as it is
+        for (data, compressed) in [(&text, true), (&noise, false)] {
+            let mut b = a.clone();
+            b[100000..200000].copy_from_slice(data);
+            let delta = write_delta_with(&a, &b, WriteOptions { compression: Some(codec), ..WriteOptions::default() });
+            assert!(codecs(&delta, data) == [compressed.then_some(codec)]);
+            assert!((delta.len() < 10000) == compressed);
+            assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+        }
+    }
+}
+
+#[test]
+fn compresses_with_file_a() {
+    let mut random = Random(0x2127599BF4325C37);
+    let a = random.bytes(400000);
+    // data which only compresses with the nearby data of file_a
+    let mut b = a.clone();
+    b.copy_within(200100..210100, 200000);
+    let dict = |op: &Op<&[u8]>| match op {
+        Op::DictDiff(payload) => Some(payload.offset()),
+        _ => None,
+    };
+
+    for in_place in [false, true] {
+        let options = WriteOptions { compression: Some(Codec::Zstd), dictionary: true, in_place, ..WriteOptions::default() };
+        let delta = write_delta_with(&a, &b, options);
+        let offsets = counts(&delta, dict);
+        assert!(offsets.len() == 1);
+        assert!(offsets[0] <= 200100 && (offsets[0] >= 200000 || !in_place));
+        assert!(delta.len() < 1000);
+        assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+        if in_place {
+            let mut target = Cursor::new(a.clone());
+            apply_in_place(&mut target, &delta[..], &ApplyOptions::default()).unwrap();
+            assert!(target.into_inner() == b);
+        }
+    }
+
+    // a short record isn't worth a dictionary
+    let mut b = a.clone();
+    b.copy_within(200100..200140, 200000);
+    let options = WriteOptions { compression: Some(Codec::Zstd), dictionary: true, ..WriteOptions::default() };
+    let delta = write_delta_with(&a, &b, options);
+    assert!(counts(&delta, dict).is_empty());
+    assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+}
+
+#[test]
+fn approx_matches_shifted_addresses() {
+    // as if the code had moved, so that every address in it has changed a little
+    let mut random = Random(0xD1B54A32D192ED03);
+    let a = random.bytes(256 * 1024);
+    let mut b = a.clone();
+    for word in b.chunks_exact_mut(64) {
+        let address = u32::from_le_bytes([word[0], word[1], word[2], word[3]]).wrapping_add(0x40);
+        word[..4].copy_from_slice(&address.to_le_bytes());
+    }
+    b.insert(1000, 0x90);
+
+    let compressed = WriteOptions { compression: Some(Codec::Zstd), ..WriteOptions::default() };
+    let plain = write_delta_with(&a, &b, compressed.clone());
+    let approx = write_delta_with(&a, &b, WriteOptions { approx: true, ..compressed });
+    assert!(counts(&approx, |op| match op {
+        Op::Add { data, .. } => Some(data.len()),
+        _ => None,
+    }).iter().sum::<u64>() > 250 * 1024);
+    assert!(apply(&a, &approx, &ApplyOptions::default()) == b);
+    assert!(approx.len() * 4 < plain.len(), "{} {}", approx.len(), plain.len());
+}
+
+/*
+ * An x86-64 ELF file, with one section of code: a CALL to each of "targets",
+ * with some other instructions between them.  "shift" moves all of the code.
+ */
+fn elf(targets: &[u32], shift: usize) -> Vec<u8> {
+    const CODE: usize = 0x1000;
+    let mut code = vec![0x90; shift];
+    for (i, target) in targets.iter().enumerate() {
+        code.extend([0x48, 0x89, 0xC7, 0x31, 0xC0, 0xBE, (i % 100) as u8, 0, 0, 0, 0xE8]);
+        let next = (code.len() + 4) as u32;
+        code.extend(target.wrapping_sub(next).to_le_bytes());
+    }
+
+    let mut elf = vec![0; CODE];
+    elf[..6].copy_from_slice(b"\x7FELF\x02\x01");
+    elf[0x12] = 62;
+    let shoff = (CODE + code.len()) as u64;
+    elf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+    elf[0x3A] = 64;
+    elf[0x3C] = 1;
+    elf.extend(&code);
+    let mut header = [0; 64];
+    header[0x04] = 1;
+    header[0x08] = 0x6;
+    header[0x18..0x20].copy_from_slice(&(CODE as u64).to_le_bytes());
+    header[0x20..0x28].copy_from_slice(&(code.len() as u64).to_le_bytes());
+    elf.extend(header);
+    elf
+}
+
+#[test]
+fn filters_code() {
+    let mut random = Random(0xA0761D6478BD642F);
+    let targets: Vec<u32> = (0..5000).map(|_| random.below(0x100000) as u32).collect();
+    let a = elf(&targets, 0);
+    // the code has moved, so that every call is to a different relative target
+    let b = elf(&targets, 16);
+
+    let options = WriteOptions { moves: true, compression: Some(Codec::Zstd), ..WriteOptions::default() };
+    let plain = write_delta_with(&a, &b, options.clone());
+    let filtered = write_delta_with(&a, &b, WriteOptions { filter: true, ..options });
+    let filters = counts(&filtered, |op| match op {
+        Op::Filter { regions_b, .. } => Some(regions_b.len() as u64),
+        _ => None,
+    });
+    assert!(filters == [1]);
+    assert!(filtered.len() * 4 < plain.len(), "{} {}", filtered.len(), plain.len());
+    assert!(apply(&a, &filtered, &ApplyOptions::default()) == b);
+}
+
+#[test]
+fn fills() {
+    let mut random = Random(0xE7037ED1A0B428DB);
+    let a = random.bytes(100000);
+    let mut b = a.clone();
+    b[10000..30000].fill(0xFF);
+    b[50000..50200].fill(b'x');
+    b.extend([0xAA; 5000]);
+
+    let delta = write_delta(&a, &b);
+    let bytes = counts(&delta, |op| match op {
+        Op::Fill { byte, .. } => Some(*byte as u64),
+        _ => None,
+    });
+    let fills = counts(&delta, |op| match op {
+        Op::Fill { count, .. } => Some(*count),
+        _ => None,
+    });
+    assert!(bytes == [0xFF, b'x' as u64, 0xAA]);
+    assert!(fills == [20000, 200, 5000]);
+    assert!(delta.len() < 200);
+    check_apply(&a, &b, &ApplyOptions::default());
+}
+
+#[test]
+fn xor_applies_both_ways() {
+    let mut random = Random(0x94D049BB133111EB);
+    for _ in 0..30 {
+        let a = random.file_a();
+        let mut b = random.file_b(&a);
+        b.resize(a.len(), 7);
+        for compression in [None, Some(Codec::Zstd)] {
+            let options = WriteOptions { xor: true, compression, ..WriteOptions::default() };
+            let delta = write_delta_with(&a, &b, options.clone());
+            let threaded = WriteOptions { threads: 3, ..options };
+            assert!(write_delta_with(&a, &b, threaded) == delta);
+            assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+
+            let mut reversed = Cursor::new(Vec::new());
+            apply_reverse_to(&mut Cursor::new(&b[..]), Cursor::new(&delta[..]), &mut reversed, &ApplyOptions::default()).unwrap();
+            assert!(reversed.into_inner() == a);
+            let mut target = Cursor::new(b.clone());
+            apply_reverse_in_place(&mut target, Cursor::new(&delta[..]), &ApplyOptions::default()).unwrap();
+            assert!(target.into_inner() == a);
+            if a != b {
+                // file_b is the precondition for going back
+                let mut target = Cursor::new(a.clone());
+                assert!(apply_reverse_in_place(&mut target, Cursor::new(&delta[..]), &ApplyOptions::default()).is_err());
+                assert!(target.into_inner() == a);
+            }
+
+            let mut optimised = Vec::new();
+            optimise_delta(&mut Cursor::new(&a[..]), &delta[..], &mut optimised).unwrap();
+            let mut reversed = Cursor::new(Vec::new());
+            apply_reverse_to(&mut Cursor::new(&b[..]), Cursor::new(&optimised[..]), &mut reversed, &ApplyOptions::default()).unwrap();
+            assert!(reversed.into_inner() == a);
+        }
+    }
+}
+
+/*
+ * The bytes of file_b which a delta gives with OP_DIFFs and OP_FILLs, rather
+ * than from file_a.
+ */
+fn changed_len(delta: &[u8]) -> u64 {
+    let mut reader = DeltaReader::new(delta).unwrap();
+    let mut len = 0;
+    loop {
+        match reader.next_op().unwrap() {
+            Op::Diff(payload) => len += payload.len(),
+            Op::Fill { count, .. } => len += count,
+            Op::End => return len,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn trims_to_changed_bytes() {
+    let mut random = Random(0x2545F4914F6CDD1D);
+    let a = random.bytes(1024 * 1024);
+    let mut b = a.clone();
+    b[123457] ^= 0x10;
+    b[987653] ^= 0x01;
+    b[987753] ^= 0x01;
+    // a chunk which differs is trimmed at its ends, so that the bytes between
+    // the last two changes are only given if they are in the same chunk
+    for (chunk_size, changed) in [(1, 3), (8, 3), (4096, 102)] {
+        let options = WriteOptions { chunk_size, ..WriteOptions::default() };
+        let delta = write_delta_with(&a, &b, options);
+        assert_eq!(changed_len(&delta), changed, "{}", chunk_size);
+        assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+    }
+}
+
+#[test]
+fn spans_on_threads() {
+    // changes on either side of the boundaries between spans
+    let mut random = Random(0x4F1BBCDCBFA53E0A);
+    let a = random.bytes(10 * 1024 * 1024 + 1000);
+    let mut b = a.clone();
+    for span in 1..=2 {
+        let boundary = span * 4 * 1024 * 1024;
+        b[boundary - 3..boundary + 5].fill(0xFF);
+        b[boundary + 100..boundary + 10000].fill(0);
+    }
+    b.truncate(9 * 1024 * 1024);
+    let delta = write_delta_with(&a, &b, WriteOptions::default());
+    for threads in [2, 3, 8] {
+        assert!(write_delta_with(&a, &b, WriteOptions { threads, ..WriteOptions::default() }) == delta);
+    }
+    assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+}
+
+#[test]
+fn rejects_bad_options() {
+    for (block_size, chunk_size) in [(0, 8), (4096, 0), (4 * 1024 * 1024 + 1, 8), (u64::MAX, 8)] {
+        let options = WriteOptions { block_size, chunk_size, ..WriteOptions::default() };
+        let result = DeltaWriter::with_options(&mut Cursor::new(b"abc"), &mut Cursor::new(b"abd"), options).write_delta(&mut Vec::new());
+        assert!(result.is_err());
+    }
+    let options = WriteOptions { threads: 0, ..WriteOptions::default() };
+    assert!(DeltaWriter::with_options(&mut Cursor::new(b"abc"), &mut Cursor::new(b"abd"), options).write_delta(&mut Vec::new()).is_err());
+    let options = WriteOptions { block_size: 4 * 1024 * 1024, ..WriteOptions::default() };
+    assert!(apply(b"abc", &write_delta_with(b"abc", b"abd", options), &ApplyOptions::default()) == b"abd");
+}
+
+#[test]
+fn optimises_old_deltas() {
+    // a delta from before the optimiser, with a short OP_SKIP between OP_DIFFs,
+    // which is smaller as part of one OP_DIFF
+    let a = b"0123456789abcdefghijklmnopqrstuv";
+    let b = b"0123456789ABCDEFghIJKLMNOPqrstuv";
+    let mut delta = b"vsdelta".to_vec();
+    delta.extend([OP_VER, 0, 10, 0, OP_LEN_A, 32, OP_HASH_A]);
+    delta.extend(blake3::hash(a).as_bytes());
+    delta.extend([OP_SKIP, 10, OP_DIFF, 6]);
+    delta.extend(b"ABCDEF");
+    delta.extend([OP_SKIP, 2, OP_DIFF, 8]);
+    delta.extend(b"IJKLMNOP");
+    delta.extend([OP_SKIP, 6, OP_LEN_B, 32, OP_HASH_B]);
+    delta.extend(blake3::hash(b).as_bytes());
+    delta.push(OP_END);
+
+    let mut optimised = Vec::new();
+    optimise_delta(&mut Cursor::new(&a[..]), &delta[..], &mut optimised).unwrap();
+    assert!(counts(&optimised, |op| match op {
+        Op::Diff(payload) => Some(payload.len()),
+        _ => None,
+    }) == [16]);
+    assert!(optimised.len() == delta.len() - 2);
+    assert!(apply(a, &optimised, &ApplyOptions::default()) == b);
+}
+
+#[test]
+fn optimises_long_deltas() {
+    // more segments than are held for plan() at once
+    let mut random = Random(0x8BB84B93962EACC9);
+    let a = random.bytes(2 * 1024 * 1024);
+    let mut b = a.clone();
+    for byte in b.iter_mut().step_by(24) {
+        *byte ^= 0x5A;
+    }
+    let delta = write_delta_with(&a, &b, WriteOptions { chunk_size: 1, ..WriteOptions::default() });
+    let mut optimised = Vec::new();
+    optimise_delta(&mut Cursor::new(&a[..]), &delta[..], &mut optimised).unwrap();
+    assert!(optimised.len() <= delta.len());
+    assert!(apply(&a, &optimised, &ApplyOptions::default()) == b);
+}
+
+#[test]
+fn maps_files() {
+    let mut random = Random(0x6A09E667F3BCC908);
+    let a = random.bytes(5 * 1024 * 1024);
+    let mut b = a.clone();
+    b[4 * 1024 * 1024..4 * 1024 * 1024 + 5000].fill(0);
+    let mut file_a = temp_file(&a);
+    let mut file_b = temp_file(&b);
+    // an empty file can't be mapped
+    assert!(unsafe { map_file(&temp_file(&[])) }.is_none());
+
+    let map_a = unsafe { map_file(&file_a) }.unwrap();
+    let map_b = unsafe { map_file(&file_b) }.unwrap();
+    assert!(map_a[..] == a[..] && map_b[..] == b[..]);
+    let mut delta = Vec::new();
+    DeltaWriter::new(&mut file_a, &mut file_b).with_memory(&map_a, &map_b).write_delta(&mut delta).unwrap();
+    assert!(delta == write_delta(&a, &b));
+    // the contents must be those of the files
+    let result = DeltaWriter::new(&mut file_a, &mut file_b).with_memory(&map_a, &map_a[1..]).write_delta(&mut Vec::new());
+    assert!(result.is_err());
+}
+
+#[test]
+fn hashes_as_compared() {
+    // a file_a longer than file_b, over several spans, is still hashed whole
+    let mut random = Random(0xBB67AE8584CAA73B);
+    let a = random.bytes(9 * 1024 * 1024 + 123);
+    let mut b = a[..7 * 1024 * 1024].to_vec();
+    b[3 * 1024 * 1024..3 * 1024 * 1024 + 777].fill(0x55);
+    for options in [WriteOptions::default(), WriteOptions { moves: true, ..WriteOptions::default() }] {
+        let delta = write_delta_with(&a, &b, options.clone());
+        assert!(apply(&a, &delta, &ApplyOptions::default()) == b);
+        for memory in [false, true] {
+            let mut file_a = Cursor::new(&a[..]);
+            let mut file_b = Cursor::new(&b[..]);
+            let mut writer = DeltaWriter::with_options(&mut file_a, &mut file_b, options.clone());
+            if memory {
+                writer = writer.with_memory(&a, &b);
+            }
+            let mut streamed = Vec::new();
+            writer.write_delta(&mut streamed).unwrap();
+            let mut seekable = Cursor::new(Vec::new());
+            writer.write_seekable_delta(&mut seekable).unwrap();
+            assert!(streamed == delta && seekable.into_inner() == delta, "memory {} {:?}", memory, options);
+        }
+    }
+}
+
+/*
+ * The extents of data, which leave any run of 50 or more zeros as a hole.
+ */
+fn extents_of(data: &[u8]) -> Vec<Range<u64>> {
+    let mut extents: Vec<Range<u64>> = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take_while(|&&byte| byte == 0).count();
+        if zeros >= 50 {
+            i += zeros;
+            continue;
+        }
+        let end = i + zeros.max(1);
+        match extents.last_mut() {
+            Some(last) if last.end == i as u64 => last.end = end as u64,
+            _ => extents.push(i as u64..end as u64),
+        }
+        i = end;
+    }
+    extents
+}
+
+#[test]
+fn holes_and_extents() {
+    let mut random = Random(0xBF58476D1CE4E5B9);
+    for _ in 0..30 {
+        let mut a = random.file_a();
+        let mut b = random.file_b(&a);
+        // holes of their own, and holes in the same places
+        let (len_a, len_b) = (a.len(), b.len());
+        let at = random.below(len_a as u64) as usize;
+        a[at..(at + 5000).min(len_a)].fill(0);
+        let at = random.below(len_b as u64) as usize;
+        b[at..(at + 5000).min(len_b)].fill(0);
+        let at = random.below(len_a.min(len_b) as u64) as usize;
+        a[at..(at + 3000).min(len_a)].fill(0);
+        b[at..(at + 3000).min(len_b)].fill(0);
+
+        let (extents_a, extents_b) = (extents_of(&a), extents_of(&b));
+        let options = WriteOptions { threads: 2, ..WriteOptions::default() };
+        let delta = write_delta_with(&a, &b, options.clone());
+        for memory in [false, true] {
+            let mut file_a = Cursor::new(&a[..]);
+            let mut file_b = Cursor::new(&b[..]);
+            let mut writer = DeltaWriter::with_options(&mut file_a, &mut file_b, options.clone());
+            if memory {
+                writer = writer.with_memory(&a, &b);
+            }
+            let mut sparse = Vec::new();
+            writer.with_extents(&extents_a, &extents_b).write_delta(&mut sparse).unwrap();
+            assert!(sparse == delta, "memory {}", memory);
+        }
+        assert!(hash_sparse_file(&mut Cursor::new(&a[..]), a.len() as u64, 2, &extents_a).unwrap() == *blake3::hash(&a).as_bytes());
+    }
+
+    // a real file, whose holes are found by data_extents()
+    let mut a = vec![0u8; 3 * 1024 * 1024];
+    a[1024 * 1024..1024 * 1024 + 5000].fill(7);
+    let mut file = temp_file(&[]);
+    file.set_len(a.len() as u64).unwrap();
+    file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+    file.write_all(&a[1024 * 1024..1024 * 1024 + 5000]).unwrap();
+    if let Some(extents) = data_extents(&file) {
+        assert!(hash_sparse_file(&mut file, a.len() as u64, 1, &extents).unwrap() == *blake3::hash(&a).as_bytes());
+    }
+    let mut b = a.clone();
+    b[2 * 1024 * 1024] = 1;
+    check_apply(&a, &b, &ApplyOptions::default());
+}
+
+#[test]
+fn punches_holes() {
+    let mut random = Random(0x94D049BB133111EB);
+    let mut a = random.bytes(256 * 1024);
+    a[64 * 1024..192 * 1024].fill(0);
+    let mut b = a.clone();
+    // an OP_HOLE, and zeros within an OP_DIFF
+    b[8 * 1024..128 * 1024].fill(0);
+    b[200 * 1024..210 * 1024].fill(0);
+    b[200 * 1024 + 4100] = 1;
+    let delta = write_delta_with(&a, &b, WriteOptions { in_place: true, ..WriteOptions::default() });
+
+    for punch_holes in [false, true] {
+        let mut target = temp_file(&a);
+        apply_in_place(&mut target, &delta[..], &ApplyOptions { punch_holes, ..ApplyOptions::default() }).unwrap();
+        assert!(contents(&mut target) == b, "punch_holes {}", punch_holes);
+    }
+}
+
+#[test]
+fn copies_from_files() {
+    // real files, whose blocks may be cloned or copied between them
+    let mut random = Random(0x8CB92BA72F3D8DD7);
+    for _ in 0..5 {
+        let mut a = random.bytes(256 * 1024);
+        a[64 * 1024..192 * 1024].fill(0);
+        let mut b = a.clone();
+        b[8 * 1024..128 * 1024].fill(0);
+        b[200 * 1024] ^= 1;
+        let delta = write_delta(&a, &b);
+
+        for sparse in [false, true] {
+            let mut output = temp_file(&[]);
+            apply_file_to(&mut temp_file(&a), &delta[..], &mut output, &ApplyOptions { sparse, ..ApplyOptions::default() }).unwrap();
+            assert!(contents(&mut output) == b, "sparse {}", sparse);
+        }
+
+        // and back again
+        let delta = write_delta_with(&a, &b, WriteOptions { xor: true, ..WriteOptions::default() });
+        let mut output = temp_file(&[]);
+        apply_reverse_file_to(&mut temp_file(&b), Cursor::new(&delta[..]), &mut output, &ApplyOptions::default()).unwrap();
+        assert!(contents(&mut output) == a);
+    }
+}
+
+/*
+ * The ranges of at least 20 bytes which are the same in both files, as if
+ * they were shared.
+ */
+fn same_ranges(a: &[u8], b: &[u8]) -> Vec<Range<u64>> {
+    let mut ranges = Vec::new();
+    let len = a.len().min(b.len());
+    let mut i = 0;
+    while i < len {
+        let same = a[i..len].iter().zip(&b[i..len]).take_while(|(x, y)| x == y).count();
+        if same >= 20 {
+            ranges.push(i as u64..(i + same) as u64);
+        }
+        i += same.max(1);
+    }
+    ranges
+}
+
+#[test]
+fn skips_shared_extents() {
+    let mut random = Random(0x2545F4914F6CDD1D);
+    for _ in 0..30 {
+        let a = random.file_a();
+        let b = random.file_b(&a);
+        let (extents_a, extents_b) = (extents_of(&a), extents_of(&b));
+        let shared = same_ranges(&a, &b);
+        let options = WriteOptions { threads: 2, ..WriteOptions::default() };
+        let delta = write_delta_with(&a, &b, options.clone());
+        for memory in [false, true] {
+            let mut file_a = Cursor::new(&a[..]);
+            let mut file_b = Cursor::new(&b[..]);
+            let mut writer = DeltaWriter::with_options(&mut file_a, &mut file_b, options.clone());
+            if memory {
+                writer = writer.with_memory(&a, &b);
+            }
+            let mut skipped = Vec::new();
+            writer.with_extents(&extents_a, &extents_b).with_shared(&shared).write_delta(&mut skipped).unwrap();
+            assert!(skipped == delta, "memory {}", memory);
+        }
+    }
+
+    // whole spans which are shared
+    let a = random.bytes(9 * 1024 * 1024);
+    let mut b = a.clone();
+    b[8 * 1024 * 1024 + 5] ^= 1;
+    b.extend(b"more");
+    let shared = same_ranges(&a, &b);
+    let mut skipped = Vec::new();
+    DeltaWriter::new(&mut Cursor::new(&a[..]), &mut Cursor::new(&b[..])).with_shared(&shared).write_delta(&mut skipped).unwrap();
+    assert!(skipped == write_delta_with(&a, &b, WriteOptions::default()));
+
+    // separate files share nothing, if their extents can be found at all
+    let a = vec![7u8; 64 * 1024];
+    let (file_a, file_b) = (temp_file(&a), temp_file(&a));
+    assert!(shared_extents(&file_a, &file_b).is_none_or(|shared| shared.is_empty()));
+}